@@ -5,10 +5,12 @@ use reqwest_middleware_cache::{managers::CACacheManager, Cache, CacheMode};
 #[tokio::main]
 async fn main() -> Result<()> {
     let client = ClientBuilder::new(Client::new())
-        .with(Cache {
-            mode: CacheMode::Default,
-            cache_manager: CACacheManager::default(),
-        })
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(CACacheManager::default())
+                .build()?,
+        )
         .build();
     client
         .get("https://developer.mozilla.org/en-US/docs/Web/HTTP/Caching")
@@ -0,0 +1,186 @@
+use std::{
+    convert::TryFrom,
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use anyhow::anyhow;
+use reqwest::{Body, Client, Request, Response, ResponseBuilderExt};
+use reqwest_middleware::{ClientBuilder, ClientWithMiddleware, Error, Middleware, Next};
+use task_local_extensions::Extensions;
+use tower::{BoxError, Layer, Service, ServiceExt};
+
+use crate::{Cache, CacheManager};
+
+/// A [`tower::Layer`] putting [`Cache`] in front of an HTTP client service, for
+/// stacks built with tower rather than reqwest-middleware.
+///
+/// The wrapped service receives `http::Request<reqwest::Body>` and may answer with
+/// any body convertible into a `reqwest::Body`. Background revalidations, from
+/// [`CacheMode::Default`](crate::CacheMode::Default) with `stale-while-revalidate`
+/// or [`CacheOptions::refresh_ahead`](crate::CacheOptions::refresh_ahead), are sent
+/// through [`CacheOptions::revalidation_client`](crate::CacheOptions::revalidation_client)
+/// instead of the wrapped service.
+///
+/// ```no_run
+/// # use reqwest_middleware_cache::{managers::HashMapManager, Cache, CacheLayer};
+/// # use tower::ServiceExt;
+/// # async fn run() -> anyhow::Result<()> {
+/// let client = tower::service_fn(|req: http::Request<reqwest::Body>| async move {
+///     http::Response::builder().body(format!("you asked for {}", req.uri()))
+/// });
+/// let cache = Cache::builder()
+///     .cache_manager(HashMapManager::default())
+///     .build()?;
+/// let service = tower::ServiceBuilder::new()
+///     .layer(CacheLayer::new(cache))
+///     .service(client);
+/// let req = http::Request::get("https://example.com/").body(reqwest::Body::empty())?;
+/// let res = service.oneshot(req).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct CacheLayer<T: CacheManager> {
+    cache: Cache<T>,
+}
+
+impl<T: CacheManager> CacheLayer<T> {
+    /// Creates a layer caching responses with `cache`.
+    pub fn new(cache: Cache<T>) -> Self {
+        CacheLayer { cache }
+    }
+}
+
+impl<S, B, T> Layer<S> for CacheLayer<T>
+where
+    S: Service<http::Request<Body>, Response = http::Response<B>> + Clone + Send + Sync + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: Into<Body> + Send + 'static,
+    T: CacheManager + Clone + Send + Sync + 'static,
+{
+    type Service = CacheService;
+
+    fn layer(&self, inner: S) -> CacheService {
+        let client = ClientBuilder::new(Client::new())
+            .with(self.cache.clone())
+            .with(Forward(inner))
+            .build();
+        CacheService { client }
+    }
+}
+
+/// The [`tower::Service`] built by [`CacheLayer`]. Responses carry the same
+/// extensions as with the middleware, like [`HitOrMiss`](crate::HitOrMiss).
+#[derive(Clone)]
+pub struct CacheService {
+    client: ClientWithMiddleware,
+}
+
+impl fmt::Debug for CacheService {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CacheService").finish()
+    }
+}
+
+impl<B: Into<Body>> Service<http::Request<B>> for CacheService {
+    type Response = http::Response<Body>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let client = self.client.clone();
+        let req = Request::try_from(req);
+        Box::pin(async move {
+            let mut res = client.execute(req?).await?;
+            let extensions = std::mem::take(res.extensions_mut());
+            let mut http_res = http::Response::new(Body::empty());
+            *http_res.status_mut() = res.status();
+            *http_res.version_mut() = res.version();
+            *http_res.headers_mut() = res.headers().clone();
+            *http_res.extensions_mut() = extensions;
+            *http_res.body_mut() = Body::from(res);
+            Ok(http_res)
+        })
+    }
+}
+
+// The end of the middleware chain, handing requests to the wrapped service
+// instead of the reqwest client.
+struct Forward<S>(S);
+
+#[async_trait::async_trait]
+impl<S, B> Middleware for Forward<S>
+where
+    S: Service<http::Request<Body>, Response = http::Response<B>> + Clone + Send + Sync + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: Into<Body> + Send + 'static,
+{
+    async fn handle(
+        &self,
+        req: Request,
+        _extensions: &mut Extensions,
+        _next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let url = req.url().clone();
+        let req = http::Request::try_from(req)?;
+        let res = self.0.clone().oneshot(req).await.map_err(|e| {
+            let e: BoxError = e.into();
+            Error::Middleware(anyhow!(e))
+        })?;
+        let (parts, body) = res.into_parts();
+        let mut res = http::Response::builder()
+            .url(url)
+            .body(body.into())
+            .map_err(|e| Error::Middleware(e.into()))?;
+        *res.status_mut() = parts.status;
+        *res.version_mut() = parts.version;
+        *res.headers_mut() = parts.headers;
+        Ok(Response::from(res))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::{managers::HashMapManager, HitOrMiss};
+
+    #[tokio::test]
+    async fn caches_responses_from_the_wrapped_service() -> anyhow::Result<()> {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let inner = tower::service_fn(move |_req: http::Request<Body>| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async {
+                http::Response::builder()
+                    .header("cache-control", "max-age=86400, public")
+                    .body("test")
+            }
+        });
+        let cache = Cache::builder()
+            .cache_manager(HashMapManager::default())
+            .build()?;
+        let mut service = CacheLayer::new(cache).layer(inner);
+
+        for expected in &[HitOrMiss::Miss, HitOrMiss::Hit] {
+            let req = http::Request::get("https://example.com/").body(Body::empty())?;
+            let res = service.ready().await?.call(req).await?;
+            assert_eq!(res.extensions().get::<HitOrMiss>(), Some(expected));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+}
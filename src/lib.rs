@@ -11,10 +11,12 @@
 //! #[tokio::main]
 //! async fn main() -> Result<()> {
 //!     let client = ClientBuilder::new(Client::new())
-//!         .with(Cache {
-//!             mode: CacheMode::Default,
-//!             cache_manager: CACacheManager::default(),
-//!         })
+//!         .with(
+//!             Cache::builder()
+//!                 .mode(CacheMode::Default)
+//!                 .cache_manager(CACacheManager::default())
+//!                 .build()?,
+//!         )
 //!         .build();
 //!     client
 //!         .get("https://developer.mozilla.org/en-US/docs/Web/HTTP/Caching")
@@ -32,31 +34,182 @@
     unused_qualifications,
     rustdoc::missing_doc_code_examples
 )]
-use std::time::SystemTime;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex as StdMutex, MutexGuard, PoisonError},
+    time::{Duration, SystemTime},
+};
 
 use anyhow::{anyhow, Result};
-use http::{header::CACHE_CONTROL, HeaderValue, Method};
+use http::{
+    header::{
+        HeaderName, ACCEPT_ENCODING, AGE, CACHE_CONTROL, CONNECTION, CONTENT_LENGTH,
+        CONTENT_LOCATION, ETAG, EXPIRES, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, LOCATION,
+        PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, RETRY_AFTER, SET_COOKIE, TE, TRAILER,
+        TRANSFER_ENCODING, UPGRADE, VARY,
+    },
+    HeaderMap, HeaderValue, Method,
+};
 use http_cache_semantics::{AfterResponse, BeforeRequest, CachePolicy};
-use reqwest::{Request, Response};
-use reqwest_middleware::{Error, Middleware, Next};
+use reqwest::{Client, Request, Response, ResponseBuilderExt, Url};
+use reqwest_middleware::{ClientWithMiddleware, Middleware, Next};
+#[cfg(feature = "hash-keys")]
+use sha2::{Digest, Sha256};
 use task_local_extensions::Extensions;
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+// Emits a `tracing` event when the `tracing` feature is enabled, and nothing otherwise.
+macro_rules! cache_event {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)*);
+    };
+}
 
 /// Backend cache managers, cacache is the default.
+/// Each manager is behind its own cargo feature, with none enabled the
+/// middleware can still be used with a custom [`CacheManager`].
 pub mod managers;
 
+#[cfg(feature = "tower")]
+mod layer;
+
+#[cfg(feature = "tower")]
+pub use layer::{CacheLayer, CacheService};
+
 /// A trait providing methods for storing, reading, and removing cache records.
+/// Records are addressed by a cache key derived from the request by [`Cache`].
 #[async_trait::async_trait]
 pub trait CacheManager {
     /// Attempts to pull a cached reponse and related policy from cache.
-    async fn get(&self, req: &Request) -> Result<Option<(Response, CachePolicy)>>;
+    /// Fresh hits hand the response to the caller as is, so a manager may return
+    /// a body streamed from storage rather than read into memory up front.
+    async fn get(&self, cache_key: &str) -> Result<Option<(Response, CachePolicy)>>;
+    /// Attempts to pull only the policy of a cached response, for checks that
+    /// don't need the response itself. Calls [`get`](CacheManager::get) by default,
+    /// managers storing bodies apart from their records can avoid reading them.
+    async fn get_policy(&self, cache_key: &str) -> Result<Option<CachePolicy>> {
+        Ok(self.get(cache_key).await?.map(|(_, policy)| policy))
+    }
     /// Attempts to cache a response and related policy.
-    async fn put(&self, req: &Request, res: Response, policy: CachePolicy) -> Result<Response>;
+    /// The body can be consumed chunk by chunk (see [`Response::chunk`]), so managers
+    /// able to write incrementally never need to hold it in memory in full.
+    async fn put(&self, cache_key: &str, res: Response, policy: CachePolicy) -> Result<Response>;
+    /// Replaces the headers and policy of a stored response, as done after a
+    /// `304 Not Modified`. `res` is the stored response as read by
+    /// [`get`](CacheManager::get) with its headers updated, its body unread.
+    /// Calls [`put`](CacheManager::put) by default, managers storing bodies apart
+    /// from their records can leave the body alone.
+    async fn update_metadata(
+        &self,
+        cache_key: &str,
+        res: Response,
+        policy: CachePolicy,
+    ) -> Result<Response> {
+        self.put(cache_key, res, policy).await
+    }
     /// Attempts to remove a record from cache.
-    async fn delete(&self, req: &Request) -> Result<()>;
+    async fn delete(&self, cache_key: &str) -> Result<()>;
+    /// Lists the keys of every record in the cache.
+    /// Fails by default, for managers that can't enumerate their records.
+    async fn keys(&self) -> Result<Vec<String>> {
+        Err(anyhow!("This cache manager does not support listing keys"))
+    }
+    /// Removes every record whose URL starts with `prefix`, going by keys in the
+    /// default `METHOD:URL` form (variants stored for `Vary` included).
+    /// Fails by default, for managers that can't enumerate their records.
+    async fn invalidate_prefix(&self, prefix: &str) -> Result<()> {
+        let _ = prefix;
+        Err(anyhow!(
+            "This cache manager does not support invalidating by prefix"
+        ))
+    }
+    /// Reports how many records the cache holds and how much space they take up.
+    /// Fails by default, for managers that can't tell.
+    async fn stats(&self) -> Result<CacheStats> {
+        Err(anyhow!(
+            "This cache manager does not support reporting stats"
+        ))
+    }
+    /// Removes every record that has gone stale, returning how many were removed.
+    /// Stale records can still be revalidated, so this trades fewer conditional
+    /// requests for a smaller cache. Fails by default, for managers that can't
+    /// enumerate their records.
+    async fn clear_expired(&self) -> Result<usize> {
+        Err(anyhow!(
+            "This cache manager does not support clearing expired records"
+        ))
+    }
+}
+
+#[async_trait::async_trait]
+impl<M: CacheManager + Send + Sync + ?Sized> CacheManager for Arc<M> {
+    async fn get(&self, cache_key: &str) -> Result<Option<(Response, CachePolicy)>> {
+        (**self).get(cache_key).await
+    }
+
+    async fn get_policy(&self, cache_key: &str) -> Result<Option<CachePolicy>> {
+        (**self).get_policy(cache_key).await
+    }
+
+    async fn put(&self, cache_key: &str, res: Response, policy: CachePolicy) -> Result<Response> {
+        (**self).put(cache_key, res, policy).await
+    }
+
+    async fn update_metadata(
+        &self,
+        cache_key: &str,
+        res: Response,
+        policy: CachePolicy,
+    ) -> Result<Response> {
+        (**self).update_metadata(cache_key, res, policy).await
+    }
+
+    async fn delete(&self, cache_key: &str) -> Result<()> {
+        (**self).delete(cache_key).await
+    }
+
+    async fn keys(&self) -> Result<Vec<String>> {
+        (**self).keys().await
+    }
+
+    async fn invalidate_prefix(&self, prefix: &str) -> Result<()> {
+        (**self).invalidate_prefix(prefix).await
+    }
+
+    async fn stats(&self) -> Result<CacheStats> {
+        (**self).stats().await
+    }
+
+    async fn clear_expired(&self) -> Result<usize> {
+        (**self).clear_expired().await
+    }
 }
 
+/// A cache manager whose type is only known at runtime, see [`Cache::boxed`].
+pub type BoxedCacheManager = Arc<dyn CacheManager + Send + Sync>;
+
 /// Similar to [make-fetch-happen cache options](https://github.com/npm/make-fetch-happen#--optscache).
 /// Passed in when the [`Cache`] struct is being built.
+///
+/// A mode can also be set for a single request by inserting it into the
+/// [`Extensions`] the request is sent with, overriding [`Cache::mode`].
+///
+/// ```no_run
+/// # use reqwest_middleware::{ClientWithMiddleware, Result};
+/// # use reqwest_middleware_cache::CacheMode;
+/// # use task_local_extensions::Extensions;
+/// # async fn run(client: ClientWithMiddleware) -> Result<()> {
+/// let mut extensions = Extensions::new();
+/// extensions.insert(CacheMode::Reload);
+/// client
+///     .get("https://developer.mozilla.org/en-US/docs/Web/HTTP/Caching")
+///     .send_with_extensions(&mut extensions)
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CacheMode {
     /// Will inspect the HTTP cache on the way to the network.
@@ -65,7 +218,10 @@ pub enum CacheMode {
     /// and a normal request otherwise.
     /// It then updates the HTTP cache with the response.
     /// If the revalidation request fails (for example, on a 500 or if you're offline),
-    /// the stale response will be returned.
+    /// the stale response will be returned, unless it requires revalidation or has been
+    /// stale for longer than its `stale-if-error` window.
+    /// A stale response still within its `stale-while-revalidate` window is
+    /// returned right away and revalidated in the background instead.
     Default,
     /// Behaves as if there is no HTTP cache at all.
     NoStore,
@@ -84,35 +240,1191 @@ pub enum CacheMode {
     /// it returns a network error. (Can only be used when request’s mode is "same-origin".
     /// Any cached redirects will be followed assuming request’s redirect mode is "follow"
     /// and the redirects do not violate request’s mode.)
+    ///
+    /// The network error is a `504 Gateway Timeout` carrying `x-cache-lookup: MISS`,
+    /// or [`NotCached`] when [`CacheOptions::not_cached_error`] is set.
     OnlyIfCached,
 }
 
+/// What a shared cache does with responses carrying `Set-Cookie`, which are
+/// usually meant for a single user. See [`CacheOptions::set_cookie`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetCookieMode {
+    /// Stores the response with its cookies, the default.
+    Store,
+    /// Stores the response without its `Set-Cookie` headers, the response
+    /// returned for the request that fetched it keeps them.
+    Strip,
+    /// Doesn't store the response.
+    Refuse,
+}
+
+impl Default for SetCookieMode {
+    fn default() -> Self {
+        SetCookieMode::Store
+    }
+}
+
+/// Header set to `MISS` on the `504 Gateway Timeout` returned when
+/// [`CacheMode::OnlyIfCached`] finds nothing in the cache, telling it apart from
+/// a gateway timeout sent by a server.
+pub const X_CACHE_LOOKUP: &str = "x-cache-lookup";
+
+/// Returned as the error when [`CacheMode::OnlyIfCached`] finds nothing in the
+/// cache and [`CacheOptions::not_cached_error`] is set. It reaches the caller as
+/// [`reqwest_middleware::Error::Middleware`], from which it can be downcast.
+///
+/// ```no_run
+/// # use reqwest_middleware::{ClientWithMiddleware, Error};
+/// # use reqwest_middleware_cache::NotCached;
+/// # async fn run(client: ClientWithMiddleware) {
+/// match client.get("https://example.com").send().await {
+///     Err(Error::Middleware(e)) if e.is::<NotCached>() => println!("not cached"),
+///     _ => {}
+/// }
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotCached;
+
+impl fmt::Display for NotCached {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("no cached response for an only-if-cached request")
+    }
+}
+
+impl std::error::Error for NotCached {}
+
+/// The ways the cache itself can fail a request. Like [`NotCached`], it reaches
+/// the caller as [`reqwest_middleware::Error::Middleware`], from which it can be
+/// downcast. Errors from the server or the network are passed through untouched.
+///
+/// ```no_run
+/// # use reqwest_middleware::{ClientWithMiddleware, Error};
+/// # use reqwest_middleware_cache::CacheError;
+/// # async fn run(client: ClientWithMiddleware) {
+/// if let Err(Error::Middleware(e)) = client.get("https://example.com").send().await {
+///     match e.downcast_ref::<CacheError>() {
+///         Some(CacheError::Backend(e)) => eprintln!("cache unavailable: {}", e),
+///         Some(CacheError::Serialization(e)) => eprintln!("bad cache record: {}", e),
+///         None => {}
+///     }
+/// }
+/// # }
+/// ```
+#[derive(Debug)]
+pub enum CacheError {
+    /// The cache manager failed to read, write or remove a record.
+    Backend(anyhow::Error),
+    /// A response couldn't be turned into a record, or a record back into a response.
+    Serialization(anyhow::Error),
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CacheError::Backend(e) => write!(f, "cache backend error: {}", e),
+            CacheError::Serialization(e) => write!(f, "cache serialization error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CacheError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CacheError::Backend(e) | CacheError::Serialization(e) => Some(e.as_ref()),
+        }
+    }
+}
+
+impl From<CacheError> for reqwest_middleware::Error {
+    fn from(e: CacheError) -> Self {
+        reqwest_middleware::Error::Middleware(e.into())
+    }
+}
+
+/// Describes how the middleware produced a response. Inserted into the
+/// extensions of every response returned by [`Cache`].
+///
+/// ```no_run
+/// # use reqwest_middleware::{ClientWithMiddleware, Result};
+/// # use reqwest_middleware_cache::HitOrMiss;
+/// # async fn run(client: ClientWithMiddleware) -> Result<()> {
+/// let res = client
+///     .get("https://developer.mozilla.org/en-US/docs/Web/HTTP/Caching")
+///     .send()
+///     .await?;
+/// if res.extensions().get::<HitOrMiss>() == Some(&HitOrMiss::Hit) {
+///     println!("served from cache");
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HitOrMiss {
+    /// The response was served from the cache without contacting the server.
+    Hit,
+    /// The response was fetched from the server.
+    Miss,
+    /// The cached response was revalidated with the server and reused.
+    Revalidated,
+}
+
+/// How fresh a response served from the cache is, as judged by its policy when it
+/// was served. Inserted into the extensions of every response taken from the cache.
+///
+/// ```no_run
+/// # use reqwest_middleware::{ClientWithMiddleware, Result};
+/// # use reqwest_middleware_cache::CacheFreshness;
+/// # async fn run(client: ClientWithMiddleware) -> Result<()> {
+/// let res = client.get("https://example.com").send().await?;
+/// if let Some(freshness) = res.extensions().get::<CacheFreshness>() {
+///     println!("served from cache, {:?} until stale", freshness.time_to_live);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheFreshness {
+    /// Time since the response was generated by the server, as sent in `Age`.
+    pub age: Duration,
+    /// Time left until the response goes stale, zero once it has.
+    pub time_to_live: Duration,
+    /// Whether the response was already stale when served.
+    pub stale: bool,
+}
+
+/// What the middleware would do with a request, as reported by [`Cache::inspect`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheDecision {
+    /// A stored response would be served without contacting the server.
+    FreshHit,
+    /// A stored response exists but would be revalidated with the server first,
+    /// or served stale as its `stale-while-revalidate` window allows.
+    StaleNeedsRevalidate,
+    /// Nothing is stored for the request, which would be sent to the server.
+    Miss,
+    /// Nothing is stored for the request, and [`CacheMode::OnlyIfCached`] would
+    /// answer with a network error rather than contacting the server.
+    OnlyIfCachedMiss,
+    /// The cache would not be consulted at all, because of the method, the mode,
+    /// a `no-store` request, the bypass header or a body that can't be keyed.
+    Bypass,
+}
+
+/// The size of a cache, as reported by [`CacheManager::stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Number of records in the cache, including those pointing at `Vary` variants.
+    pub entries: u64,
+    /// Bytes taken up by the records and their bodies.
+    pub bytes: u64,
+}
+
 /// Caches requests according to http spec
-#[derive(Debug, Clone)]
 pub struct Cache<T: CacheManager> {
-    /// Determines the manager behavior
+    /// Determines the manager behavior, unless a [`CacheMode`] is present
+    /// in the request [`Extensions`] or set for its host in
+    /// [`CacheOptions::host_modes`]
     pub mode: CacheMode,
-    /// Manager instance that implements the CacheManager trait
-    pub cache_manager: T,
+    /// Manager instance that implements the CacheManager trait, shared with the
+    /// background revalidations the middleware spawns
+    pub cache_manager: Arc<T>,
+    /// Fine tunes which requests and responses are cached
+    pub options: CacheOptions,
+}
+
+// Clones share the manager, which doesn't need to be `Clone` itself.
+impl<T: CacheManager> Clone for Cache<T> {
+    fn clone(&self) -> Self {
+        Cache {
+            mode: self.mode,
+            cache_manager: self.cache_manager.clone(),
+            options: self.options.clone(),
+        }
+    }
+}
+
+// Managers often hold clients or pools that aren't `Debug`, so the manager is
+// left out rather than required to be.
+impl<T: CacheManager> fmt::Debug for Cache<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cache")
+            .field("mode", &self.mode)
+            .field("cache_manager", &std::any::type_name::<T>())
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+/// Options of the policies built for every response, from
+/// [http-cache-semantics](https://github.com/kornelski/rusty-http-cache-semantics),
+/// see [`CacheOptions::policy_options`].
+pub use http_cache_semantics::CacheOptions as CachePolicyOptions;
+
+/// Derives the key a request's response is cached under.
+pub type CacheKey = Arc<dyn Fn(&Request) -> String + Send + Sync>;
+
+/// Called with the request when the middleware reaches a given outcome, see
+/// [`CacheOptions::on_hit`] and friends. Hooks run inline on the request path,
+/// so they should return quickly, e.g. by bumping a counter.
+pub type CacheHook = Arc<dyn Fn(&Request) + Send + Sync>;
+
+/// Decides whether a response that could be stored should be, see
+/// [`CacheOptions::should_cache`].
+pub type CachePredicate = Arc<dyn Fn(&Request, &Response) -> bool + Send + Sync>;
+
+/// Picks the freshness lifetime of a response, see [`CacheOptions::override_ttl`].
+pub type CacheTtl = Arc<dyn Fn(&Request, &Response) -> Option<Duration> + Send + Sync>;
+
+/// Source of the current time for every freshness decision the middleware makes.
+/// [`SystemClock`] is used unless another one is set with [`CacheBuilder::clock`],
+/// tests can supply a clock they advance by hand to step a response from fresh to
+/// stale without sleeping.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> SystemTime;
+}
+
+/// [`Clock`] reading the system time.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Configuration for a [`Cache`] beyond its mode and manager.
+#[derive(Clone)]
+pub struct CacheOptions {
+    /// Request methods whose responses may be cached, `GET` and `HEAD` by default.
+    /// Successful requests with any other method remove the cached record for their key.
+    pub cacheable_methods: Vec<Method>,
+    /// Modes used instead of [`Cache::mode`] for requests to the given hosts,
+    /// matched against the whole host name in lowercase. A [`CacheMode`] in the
+    /// request [`Extensions`] still takes precedence.
+    pub host_modes: HashMap<String, CacheMode>,
+    /// Overrides how the cache key is derived from a request, `METHOD:URL` by default.
+    pub cache_key: Option<CacheKey>,
+    /// Sorts the query parameters of the URL in the default cache key, so the same
+    /// parameters given in a different order share an entry.
+    pub sort_query_params: bool,
+    /// Query parameters left out of the URL in the default cache key, such as
+    /// tracking parameters. A name ending in `*` matches every parameter starting
+    /// with what comes before it, e.g. `utm_*`.
+    pub ignored_query_params: Vec<String>,
+    /// Treats URLs differing only by a trailing slash, or by an empty query (`?`),
+    /// as the same in the default cache key: `/docs/` and `/docs?` are keyed as
+    /// `/docs`. Default ports need no such option, they are never part of a parsed
+    /// URL. Leave this off for servers serving different content for those forms,
+    /// or set [`CacheOptions::cache_key`] for rules of your own.
+    pub canonicalize_urls: bool,
+    /// Partitions the cache, every key (custom ones included) is prefixed with
+    /// `namespace/` so caches with different namespaces never see each other's
+    /// records, even when sharing a manager. Changing it amounts to starting with
+    /// an empty cache. It shouldn't contain `:`, which ends the prefix managers
+    /// skip when matching keys by URL.
+    pub namespace: Option<String>,
+    /// Adds the request's `Accept-Encoding` to every cache key, as if all responses
+    /// carried `Vary: Accept-Encoding`, for servers that encode bodies differently
+    /// per request without saying so. Only headers set on the request are seen,
+    /// not those added by reqwest itself when sending it.
+    pub accept_encoding_in_key: bool,
+    /// Shortens the URL in the default cache key to its first 64 characters followed
+    /// by `#` and the SHA-256 hex digest of the whole URL, so keys stay short enough
+    /// for backends that limit their length, such as file names. Matching keys by
+    /// URL prefix, e.g. with [`CacheManager::invalidate_prefix`], then only works
+    /// for prefixes within those first characters. Requires the `hash-keys` feature.
+    pub hash_keys: bool,
+    /// Adds the SHA-256 hex digest of the request body to the cache key, so requests
+    /// to the same URL with different bodies, like GraphQL queries sent with `GET`,
+    /// get entries of their own. Requests with a streamed body can't be keyed this
+    /// way and skip the cache. Off by default, requires the `hash-keys` feature.
+    pub body_in_key: bool,
+    /// Client used to revalidate responses served under `stale-while-revalidate`,
+    /// or refreshed ahead with [`refresh_ahead`](CacheOptions::refresh_ahead), once
+    /// the cached response has been returned. These requests don't go through the
+    /// middleware stack, so the client should be configured like the one wrapped
+    /// by it (proxy, TLS, timeouts, default headers). Nothing is revalidated in the
+    /// background when unset: stale responses are revalidated before being served
+    /// and responses aren't refreshed ahead.
+    pub revalidation_client: Option<Client>,
+    /// Freshness lifetime given to `200` responses to `GET` and `HEAD` requests that
+    /// carry neither `Cache-Control` nor `Expires`. Unset by default, leaving such
+    /// responses to the usual heuristics.
+    pub default_ttl: Option<Duration>,
+    /// Freshness lifetime given to error responses with a status listed in
+    /// [`negative_statuses`](CacheOptions::negative_statuses) that carry neither
+    /// `Cache-Control` nor `Expires`, so a missing or failing resource isn't asked
+    /// for again on every request. A `Retry-After` on the response is used instead
+    /// when present. Unset by default, leaving error responses uncached.
+    pub negative_ttl: Option<Duration>,
+    /// Statuses cached for [`negative_ttl`](CacheOptions::negative_ttl), `404`,
+    /// `410`, `500`, `502`, `503` and `504` by default. `401` and `403` are left
+    /// out, their response usually depends on who is asking.
+    pub negative_statuses: Vec<http::StatusCode>,
+    /// Called with the request and every fetched response, a returned lifetime
+    /// replaces the one given by the response's headers, whatever they say. The
+    /// policy is stored with that lifetime, so later requests are judged by it.
+    /// `None` leaves the response to the usual rules.
+    pub override_ttl: Option<CacheTtl>,
+    /// Called when a response is served from the cache without revalidation.
+    pub on_hit: Option<CacheHook>,
+    /// Called when a response is fetched from the server.
+    pub on_miss: Option<CacheHook>,
+    /// Called when a stored response is confirmed by a `304 Not Modified`.
+    pub on_revalidated: Option<CacheHook>,
+    /// Called when revalidating a stored response fails with a server error or
+    /// a network error.
+    pub on_revalidation_failed: Option<CacheHook>,
+    /// Called when a stale response is served, either during `stale-while-revalidate`
+    /// or in place of a failed revalidation.
+    pub on_stale: Option<CacheHook>,
+    /// Tells the time when checking freshness, storing responses and dating
+    /// warnings, [`SystemClock`] by default.
+    pub clock: Arc<dyn Clock>,
+    /// Requests carrying this header skip the cache as if sent with
+    /// [`CacheMode::NoStore`]. The header is removed before the request is forwarded.
+    pub bypass_header: Option<HeaderName>,
+    /// Fail [`CacheMode::OnlyIfCached`] requests that miss the cache with [`NotCached`]
+    /// instead of returning a `504 Gateway Timeout`.
+    pub not_cached_error: bool,
+    /// Pseudonym used as the warn-agent of the `Warning` headers added to responses,
+    /// the host of the request when unset.
+    pub warn_agent: Option<String>,
+    /// Add `Warning` headers to responses served stale or without revalidation, the
+    /// default. Turn off to serve responses exactly as they were cached.
+    pub warnings: bool,
+    /// Largest response body stored, in bytes. Larger responses are passed through
+    /// without being cached. Going by `Content-Length` when the response has one,
+    /// bodies without it are read into memory to be measured.
+    pub max_body_bytes: Option<usize>,
+    /// Lets only one request per cache key through at a time, see [`SingleFlight`].
+    pub single_flight: Option<SingleFlight>,
+    /// Whether responses are judged as by a cache shared between users, the default.
+    /// A shared cache never stores `private` responses, nor responses to requests
+    /// carrying `Authorization` unless they allow it with `public`, `s-maxage` or
+    /// `must-revalidate` (https://tools.ietf.org/html/rfc7234#section-3.2), and
+    /// prefers `s-maxage` over `max-age`. Set this to `false` for a cache serving a
+    /// single user.
+    pub shared: bool,
+    /// Options for the policies built for responses, such as the fraction of their
+    /// age that responses without explicit freshness stay fresh for
+    /// (`cache_heuristic`). Its `shared` flag is replaced by [`CacheOptions::shared`].
+    pub policy_options: CachePolicyOptions,
+    /// Serve responses from the cache without ever writing to it, e.g. when another
+    /// process owns the writes to a shared backend. Misses and revalidations still go
+    /// to the server, their responses just aren't stored and nothing is invalidated.
+    pub read_only: bool,
+    /// Treat errors from the cache manager as a miss, or as a no-op when writing,
+    /// so requests still reach the server while the backend is unavailable.
+    /// Writing then holds each stored body in memory in full, revalidating doesn't:
+    /// when updating the stored headers fails, the body is read from the cache once
+    /// more. Off by default, failing the request with the backend error.
+    pub fail_open: bool,
+    /// Fraction of its freshness lifetime after which a fresh response is refreshed
+    /// in the background, while still being served from the cache. With `0.8`, a
+    /// response fresh for ten minutes is refreshed by requests made after eight.
+    /// Only applies in [`CacheMode::Default`] with a
+    /// [`revalidation_client`](CacheOptions::revalidation_client) set.
+    pub refresh_ahead: Option<f32>,
+    /// What happens to responses carrying `Set-Cookie` when the cache is
+    /// [`shared`](CacheOptions::shared), a private cache always stores them as is.
+    pub set_cookie: SetCookieMode,
+    /// Honor the `Surrogate-Control` header meant for caches run by the server's
+    /// operator, like a CDN. Its `max-age` replaces the freshness lifetime given by
+    /// `Cache-Control` and `Expires`, and its `no-store` keeps the response out of
+    /// the cache. Other directives are ignored.
+    pub surrogate_control: bool,
+    /// Headers removed from responses before they're stored, so they are never
+    /// replayed from the cache. The response returned for the request that stored
+    /// it goes without them too. Defaults to the hop-by-hop headers of
+    /// [RFC 7230 §6.1](https://tools.ietf.org/html/rfc7230#section-6.1), and while
+    /// `Connection` is listed the headers it names are removed as well.
+    pub stripped_headers: Vec<HeaderName>,
+    /// Consulted with the request and the fetched response once every other check
+    /// has found the response storable, it is only stored when this returns `true`.
+    /// Only the status and headers are available, the body is yet to be read.
+    pub should_cache: Option<CachePredicate>,
+}
+
+impl fmt::Debug for CacheOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CacheOptions")
+            .field("cacheable_methods", &self.cacheable_methods)
+            .field("host_modes", &self.host_modes)
+            .field(
+                "cache_key",
+                &self.cache_key.as_ref().map(|_| "Fn(&Request) -> String"),
+            )
+            .field("sort_query_params", &self.sort_query_params)
+            .field("ignored_query_params", &self.ignored_query_params)
+            .field("canonicalize_urls", &self.canonicalize_urls)
+            .field("namespace", &self.namespace)
+            .field("accept_encoding_in_key", &self.accept_encoding_in_key)
+            .field("hash_keys", &self.hash_keys)
+            .field("body_in_key", &self.body_in_key)
+            .field("revalidation_client", &self.revalidation_client)
+            .field("default_ttl", &self.default_ttl)
+            .field("negative_ttl", &self.negative_ttl)
+            .field("negative_statuses", &self.negative_statuses)
+            .field(
+                "override_ttl",
+                &self
+                    .override_ttl
+                    .as_ref()
+                    .map(|_| "Fn(&Request, &Response) -> Option<Duration>"),
+            )
+            .field("on_hit", &hook_name(&self.on_hit))
+            .field("on_miss", &hook_name(&self.on_miss))
+            .field("on_revalidated", &hook_name(&self.on_revalidated))
+            .field(
+                "on_revalidation_failed",
+                &hook_name(&self.on_revalidation_failed),
+            )
+            .field("on_stale", &hook_name(&self.on_stale))
+            .field("clock", &self.clock)
+            .field("bypass_header", &self.bypass_header)
+            .field("not_cached_error", &self.not_cached_error)
+            .field("warn_agent", &self.warn_agent)
+            .field("warnings", &self.warnings)
+            .field("max_body_bytes", &self.max_body_bytes)
+            .field("single_flight", &self.single_flight)
+            .field("shared", &self.shared)
+            .field("policy_options", &self.policy_options)
+            .field("read_only", &self.read_only)
+            .field("fail_open", &self.fail_open)
+            .field("refresh_ahead", &self.refresh_ahead)
+            .field("set_cookie", &self.set_cookie)
+            .field("surrogate_control", &self.surrogate_control)
+            .field("stripped_headers", &self.stripped_headers)
+            .field(
+                "should_cache",
+                &self
+                    .should_cache
+                    .as_ref()
+                    .map(|_| "Fn(&Request, &Response) -> bool"),
+            )
+            .finish()
+    }
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        CacheOptions {
+            cacheable_methods: vec![Method::GET, Method::HEAD],
+            host_modes: HashMap::new(),
+            cache_key: None,
+            sort_query_params: false,
+            ignored_query_params: Vec::new(),
+            canonicalize_urls: false,
+            namespace: None,
+            accept_encoding_in_key: false,
+            hash_keys: false,
+            body_in_key: false,
+            revalidation_client: None,
+            default_ttl: None,
+            negative_ttl: None,
+            negative_statuses: vec![
+                http::StatusCode::NOT_FOUND,
+                http::StatusCode::GONE,
+                http::StatusCode::INTERNAL_SERVER_ERROR,
+                http::StatusCode::BAD_GATEWAY,
+                http::StatusCode::SERVICE_UNAVAILABLE,
+                http::StatusCode::GATEWAY_TIMEOUT,
+            ],
+            override_ttl: None,
+            on_hit: None,
+            on_miss: None,
+            on_revalidated: None,
+            on_revalidation_failed: None,
+            on_stale: None,
+            clock: Arc::new(SystemClock),
+            bypass_header: None,
+            not_cached_error: false,
+            warn_agent: None,
+            warnings: true,
+            max_body_bytes: None,
+            single_flight: None,
+            shared: true,
+            policy_options: CachePolicyOptions::default(),
+            read_only: false,
+            fail_open: false,
+            refresh_ahead: None,
+            set_cookie: SetCookieMode::default(),
+            surrogate_control: false,
+            stripped_headers: vec![
+                CONNECTION,
+                HeaderName::from_static("keep-alive"),
+                PROXY_AUTHENTICATE,
+                PROXY_AUTHORIZATION,
+                TE,
+                TRAILER,
+                TRANSFER_ENCODING,
+                UPGRADE,
+            ],
+            should_cache: None,
+        }
+    }
+}
+
+impl CacheOptions {
+    fn is_cacheable_method(&self, method: &Method) -> bool {
+        self.cacheable_methods.contains(method)
+    }
+
+    // Whether the request can be keyed, which a streamed body prevents when it
+    // should be part of the key, as does lacking the `hash-keys` feature.
+    fn is_keyable(&self, req: &Request) -> bool {
+        !self.body_in_key
+            || (cfg!(feature = "hash-keys")
+                && req.body().map_or(true, |body| body.as_bytes().is_some()))
+    }
+
+    fn is_negatively_cached(&self, status: http::StatusCode) -> bool {
+        self.negative_ttl.is_some() && self.negative_statuses.contains(&status)
+    }
+
+    fn cache_key(&self, req: &Request) -> String {
+        let mut key = match &self.cache_key {
+            Some(cache_key) => cache_key(req),
+            None => {
+                let mut url = normalize_url(
+                    req.url(),
+                    self.sort_query_params,
+                    &self.ignored_query_params,
+                );
+                if self.canonicalize_urls {
+                    url = canonicalize_url(url);
+                }
+                if self.hash_keys {
+                    hashed_req_key(req, &url)
+                } else {
+                    req_key(req, &url)
+                }
+            }
+        };
+        if self.accept_encoding_in_key {
+            key = vary_key(&key, req, &[ACCEPT_ENCODING.as_str().to_owned()]);
+        }
+        #[cfg(feature = "hash-keys")]
+        {
+            if self.body_in_key {
+                if let Some(body) = req.body().and_then(|body| body.as_bytes()) {
+                    key.push_str(&format!("|body={:x}", Sha256::digest(body)));
+                }
+            }
+        }
+        match &self.namespace {
+            Some(namespace) => format!("{}/{}", namespace, key),
+            None => key,
+        }
+    }
+}
+
+/// Coalesces concurrent requests for the same cache key. While one request for a key
+/// looks up, fetches and stores its response, the others wait for it and then find
+/// the stored response in the cache. Responses that can't be stored are still fetched
+/// once per request, one after the other.
+///
+/// Clones share the requests in flight, so one [`SingleFlight`] can coordinate several
+/// caches backed by the same manager.
+#[derive(Debug, Clone, Default)]
+pub struct SingleFlight {
+    flights: Arc<StdMutex<HashMap<String, Arc<AsyncMutex<()>>>>>,
+}
+
+impl SingleFlight {
+    /// Creates a coordinator with no requests in flight.
+    pub fn new() -> Self {
+        SingleFlight::default()
+    }
+
+    fn flights(&self) -> MutexGuard<'_, HashMap<String, Arc<AsyncMutex<()>>>> {
+        // The map is never left half-updated, so a panic elsewhere doesn't matter.
+        self.flights.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Waits until no other request for `key` is in flight.
+    async fn acquire(&self, key: String) -> Flight {
+        let lock = self
+            .flights()
+            .entry(key.clone())
+            .or_insert_with(Arc::default)
+            .clone();
+        let guard = lock.clone().lock_owned().await;
+        Flight {
+            single_flight: self.clone(),
+            key,
+            lock,
+            guard: Some(guard),
+        }
+    }
+}
+
+// A request in flight. Dropping it, including while unwinding from a panic or on an
+// early return with an error, lets the next request for the key through, and the
+// last one out removes the key from the map.
+struct Flight {
+    single_flight: SingleFlight,
+    key: String,
+    lock: Arc<AsyncMutex<()>>,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for Flight {
+    fn drop(&mut self) {
+        drop(self.guard.take());
+        let mut flights = self.single_flight.flights();
+        // Held by the map and this flight only, nobody else is waiting.
+        let unused = flights.get(&self.key).map_or(false, |lock| {
+            Arc::ptr_eq(lock, &self.lock) && Arc::strong_count(lock) == 2
+        });
+        if unused {
+            flights.remove(&self.key);
+        }
+    }
+}
+
+fn hook_name(hook: &Option<CacheHook>) -> Option<&'static str> {
+    hook.as_ref().map(|_| "Fn(&Request)")
+}
+
+fn call_hook(hook: &Option<CacheHook>, req: &Request) {
+    if let Some(hook) = hook {
+        hook(req);
+    }
+}
+
+/// Builds a [`Cache`], see [`Cache::builder`].
+#[derive(Debug, Clone)]
+pub struct CacheBuilder<T: CacheManager> {
+    mode: CacheMode,
+    cache_manager: Option<T>,
+    options: CacheOptions,
+}
+
+impl<T: CacheManager> Default for CacheBuilder<T> {
+    fn default() -> Self {
+        CacheBuilder {
+            mode: CacheMode::Default,
+            cache_manager: None,
+            options: CacheOptions::default(),
+        }
+    }
+}
+
+impl<T: CacheManager> CacheBuilder<T> {
+    /// Sets the [`CacheMode`], [`CacheMode::Default`] if not set.
+    pub fn mode(mut self, mode: CacheMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Uses `mode` for requests to `host` instead of the mode of the cache, see
+    /// [`CacheOptions::host_modes`].
+    ///
+    /// ```no_run
+    /// # use reqwest_middleware_cache::{managers::CACacheManager, Cache, CacheMode};
+    /// # fn run() -> anyhow::Result<()> {
+    /// let cache = Cache::builder()
+    ///     .cache_manager(CACacheManager::default())
+    ///     .host_mode("static.example.com", CacheMode::ForceCache)
+    ///     .host_mode("live.example.com", CacheMode::NoStore)
+    ///     .build()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn host_mode(mut self, host: impl Into<String>, mode: CacheMode) -> Self {
+        self.options
+            .host_modes
+            .insert(host.into().to_ascii_lowercase(), mode);
+        self
+    }
+
+    /// Sets the manager used to store and read responses.
+    pub fn cache_manager(mut self, cache_manager: T) -> Self {
+        self.cache_manager = Some(cache_manager);
+        self
+    }
+
+    /// Replaces all [`CacheOptions`] at once.
+    pub fn options(mut self, options: CacheOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Sets the request methods whose responses may be cached.
+    pub fn cacheable_methods(mut self, methods: impl IntoIterator<Item = Method>) -> Self {
+        self.options.cacheable_methods = methods.into_iter().collect();
+        self
+    }
+
+    /// Sets a function deriving the cache key from a request, replacing the
+    /// default `METHOD:URL`.
+    ///
+    /// ```no_run
+    /// # use reqwest_middleware_cache::{managers::CACacheManager, Cache};
+    /// # fn main() -> anyhow::Result<()> {
+    /// let cache = Cache::builder()
+    ///     .cache_manager(CACacheManager::default())
+    ///     .cache_key(|req| {
+    ///         let tenant = req
+    ///             .headers()
+    ///             .get("x-tenant-id")
+    ///             .and_then(|val| val.to_str().ok())
+    ///             .unwrap_or_default();
+    ///         format!("{}:{}:{}", tenant, req.method(), req.url())
+    ///     })
+    ///     .build()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn cache_key(
+        mut self,
+        cache_key: impl Fn(&Request) -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.options.cache_key = Some(Arc::new(cache_key));
+        self
+    }
+
+    /// Sets whether query parameters are sorted in the default cache key, see
+    /// [`CacheOptions::sort_query_params`].
+    pub fn sort_query_params(mut self, sort: bool) -> Self {
+        self.options.sort_query_params = sort;
+        self
+    }
+
+    /// Sets the query parameters left out of the default cache key, see
+    /// [`CacheOptions::ignored_query_params`].
+    ///
+    /// ```no_run
+    /// # use reqwest_middleware_cache::{managers::CACacheManager, Cache};
+    /// # fn main() -> anyhow::Result<()> {
+    /// let cache = Cache::builder()
+    ///     .cache_manager(CACacheManager::default())
+    ///     .ignored_query_params(["utm_*", "cachebust"])
+    ///     .build()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn ignored_query_params(
+        mut self,
+        names: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.options.ignored_query_params = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets whether trailing slashes and empty queries are ignored in the default
+    /// cache key, see [`CacheOptions::canonicalize_urls`].
+    pub fn canonicalize_urls(mut self, canonicalize: bool) -> Self {
+        self.options.canonicalize_urls = canonicalize;
+        self
+    }
+
+    /// Sets the namespace the cache keys are prefixed with, see
+    /// [`CacheOptions::namespace`].
+    pub fn namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.options.namespace = Some(namespace.into());
+        self
+    }
+
+    /// Sets whether `Accept-Encoding` is part of every cache key, see
+    /// [`CacheOptions::accept_encoding_in_key`].
+    pub fn accept_encoding_in_key(mut self, enabled: bool) -> Self {
+        self.options.accept_encoding_in_key = enabled;
+        self
+    }
+
+    /// Sets whether URLs are hashed in the default cache key, see
+    /// [`CacheOptions::hash_keys`].
+    pub fn hash_keys(mut self, hash_keys: bool) -> Self {
+        self.options.hash_keys = hash_keys;
+        self
+    }
+
+    /// Sets whether the request body is part of the cache key, see
+    /// [`CacheOptions::body_in_key`].
+    pub fn body_in_key(mut self, body_in_key: bool) -> Self {
+        self.options.body_in_key = body_in_key;
+        self
+    }
+
+    /// Sets the client used for background revalidation, see
+    /// [`CacheOptions::revalidation_client`].
+    pub fn revalidation_client(mut self, client: Client) -> Self {
+        self.options.revalidation_client = Some(client);
+        self
+    }
+
+    /// Sets the freshness lifetime of responses without caching headers, see
+    /// [`CacheOptions::default_ttl`].
+    pub fn default_ttl(mut self, ttl: Duration) -> Self {
+        self.options.default_ttl = Some(ttl);
+        self
+    }
+
+    /// Sets the freshness lifetime of error responses without caching headers, see
+    /// [`CacheOptions::negative_ttl`].
+    pub fn negative_ttl(mut self, ttl: Duration) -> Self {
+        self.options.negative_ttl = Some(ttl);
+        self
+    }
+
+    /// Sets the statuses cached for the negative TTL, see
+    /// [`CacheOptions::negative_statuses`].
+    pub fn negative_statuses(
+        mut self,
+        statuses: impl IntoIterator<Item = http::StatusCode>,
+    ) -> Self {
+        self.options.negative_statuses = statuses.into_iter().collect();
+        self
+    }
+
+    /// Sets a hook choosing the freshness lifetime of responses, see
+    /// [`CacheOptions::override_ttl`].
+    ///
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// # use reqwest_middleware_cache::{managers::CACacheManager, Cache};
+    /// # fn run() -> anyhow::Result<()> {
+    /// let cache = Cache::builder()
+    ///     .cache_manager(CACacheManager::default())
+    ///     .override_ttl(|req, _res| {
+    ///         req.url()
+    ///             .path()
+    ///             .starts_with("/static/")
+    ///             .then(|| Duration::from_secs(7 * 24 * 3600))
+    ///     })
+    ///     .build()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn override_ttl(
+        mut self,
+        hook: impl Fn(&Request, &Response) -> Option<Duration> + Send + Sync + 'static,
+    ) -> Self {
+        self.options.override_ttl = Some(Arc::new(hook));
+        self
+    }
+
+    /// Sets a hook called on every cache hit, see [`CacheOptions::on_hit`].
+    ///
+    /// ```no_run
+    /// # use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+    /// # use reqwest_middleware_cache::{managers::CACacheManager, Cache};
+    /// # fn main() -> anyhow::Result<()> {
+    /// let hits = Arc::new(AtomicUsize::new(0));
+    /// let counter = hits.clone();
+    /// let cache = Cache::builder()
+    ///     .cache_manager(CACacheManager::default())
+    ///     .on_hit(move |_req| {
+    ///         counter.fetch_add(1, Ordering::Relaxed);
+    ///     })
+    ///     .build()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn on_hit(mut self, hook: impl Fn(&Request) + Send + Sync + 'static) -> Self {
+        self.options.on_hit = Some(Arc::new(hook));
+        self
+    }
+
+    /// Sets a hook called on every cache miss, see [`CacheOptions::on_miss`].
+    pub fn on_miss(mut self, hook: impl Fn(&Request) + Send + Sync + 'static) -> Self {
+        self.options.on_miss = Some(Arc::new(hook));
+        self
+    }
+
+    /// Sets a hook called on every successful revalidation, see
+    /// [`CacheOptions::on_revalidated`].
+    pub fn on_revalidated(mut self, hook: impl Fn(&Request) + Send + Sync + 'static) -> Self {
+        self.options.on_revalidated = Some(Arc::new(hook));
+        self
+    }
+
+    /// Sets a hook called on every failed revalidation, see
+    /// [`CacheOptions::on_revalidation_failed`].
+    pub fn on_revalidation_failed(
+        mut self,
+        hook: impl Fn(&Request) + Send + Sync + 'static,
+    ) -> Self {
+        self.options.on_revalidation_failed = Some(Arc::new(hook));
+        self
+    }
+
+    /// Sets a hook called whenever a stale response is served, see
+    /// [`CacheOptions::on_stale`].
+    pub fn on_stale(mut self, hook: impl Fn(&Request) + Send + Sync + 'static) -> Self {
+        self.options.on_stale = Some(Arc::new(hook));
+        self
+    }
+
+    /// Sets the [`Clock`] the middleware tells the time with, see [`CacheOptions::clock`].
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.options.clock = Arc::new(clock);
+        self
+    }
+
+    /// Sets the header that makes a request skip the cache, see
+    /// [`CacheOptions::bypass_header`].
+    ///
+    /// ```no_run
+    /// # use reqwest_middleware_cache::{managers::CACacheManager, Cache};
+    /// # fn main() -> anyhow::Result<()> {
+    /// let cache = Cache::builder()
+    ///     .cache_manager(CACacheManager::default())
+    ///     .bypass_header(reqwest::header::HeaderName::from_static("x-cache-bypass"))
+    ///     .build()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn bypass_header(mut self, name: HeaderName) -> Self {
+        self.options.bypass_header = Some(name);
+        self
+    }
+
+    /// Sets whether [`CacheMode::OnlyIfCached`] misses fail with [`NotCached`], see
+    /// [`CacheOptions::not_cached_error`].
+    pub fn not_cached_error(mut self, not_cached_error: bool) -> Self {
+        self.options.not_cached_error = not_cached_error;
+        self
+    }
+
+    /// Sets the pseudonym naming the cache in `Warning` headers, see
+    /// [`CacheOptions::warn_agent`].
+    pub fn warn_agent(mut self, pseudonym: impl Into<String>) -> Self {
+        self.options.warn_agent = Some(pseudonym.into());
+        self
+    }
+
+    /// Sets whether `Warning` headers are added, see [`CacheOptions::warnings`].
+    pub fn warnings(mut self, warnings: bool) -> Self {
+        self.options.warnings = warnings;
+        self
+    }
+
+    /// Sets the largest response body stored, see [`CacheOptions::max_body_bytes`].
+    pub fn max_body_bytes(mut self, max: usize) -> Self {
+        self.options.max_body_bytes = Some(max);
+        self
+    }
+
+    /// Sets whether concurrent requests for the same key are coalesced, see
+    /// [`SingleFlight`]. Use [`CacheBuilder::options`] to share one [`SingleFlight`]
+    /// between several caches.
+    pub fn single_flight(mut self, enabled: bool) -> Self {
+        self.options.single_flight = if enabled {
+            Some(SingleFlight::new())
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Sets whether the cache is shared between users, see [`CacheOptions::shared`].
+    pub fn shared(mut self, shared: bool) -> Self {
+        self.options.shared = shared;
+        self
+    }
+
+    /// Sets the options of the policies built for responses, see
+    /// [`CacheOptions::policy_options`].
+    ///
+    /// ```no_run
+    /// # use reqwest_middleware_cache::{managers::CACacheManager, Cache, CachePolicyOptions};
+    /// # fn main() -> anyhow::Result<()> {
+    /// let cache = Cache::builder()
+    ///     .cache_manager(CACacheManager::default())
+    ///     .policy_options(CachePolicyOptions {
+    ///         cache_heuristic: 0.05,
+    ///         ..Default::default()
+    ///     })
+    ///     .build()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn policy_options(mut self, options: CachePolicyOptions) -> Self {
+        self.options.policy_options = options;
+        self
+    }
+
+    /// Sets whether the cache is only read from, see [`CacheOptions::read_only`].
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.options.read_only = read_only;
+        self
+    }
+
+    /// Sets whether cache manager errors are ignored, see [`CacheOptions::fail_open`].
+    pub fn fail_open(mut self, fail_open: bool) -> Self {
+        self.options.fail_open = fail_open;
+        self
+    }
+
+    /// Sets the fraction of the freshness lifetime after which responses are
+    /// refreshed ahead of going stale, see [`CacheOptions::refresh_ahead`].
+    pub fn refresh_ahead(mut self, threshold: f32) -> Self {
+        self.options.refresh_ahead = Some(threshold);
+        self
+    }
+
+    /// Sets how responses carrying `Set-Cookie` are stored, see
+    /// [`CacheOptions::set_cookie`].
+    pub fn set_cookie(mut self, mode: SetCookieMode) -> Self {
+        self.options.set_cookie = mode;
+        self
+    }
+
+    /// Sets whether `Surrogate-Control` is honored, see
+    /// [`CacheOptions::surrogate_control`].
+    pub fn surrogate_control(mut self, enabled: bool) -> Self {
+        self.options.surrogate_control = enabled;
+        self
+    }
+
+    /// Sets the headers removed from responses before they're stored, see
+    /// [`CacheOptions::stripped_headers`].
+    pub fn stripped_headers(mut self, names: Vec<HeaderName>) -> Self {
+        self.options.stripped_headers = names;
+        self
+    }
+
+    /// Sets a predicate vetoing the storage of responses, see
+    /// [`CacheOptions::should_cache`].
+    ///
+    /// ```no_run
+    /// # use reqwest_middleware_cache::{managers::CACacheManager, Cache};
+    /// # fn run() -> anyhow::Result<()> {
+    /// let cache = Cache::builder()
+    ///     .cache_manager(CACacheManager::default())
+    ///     .should_cache(|_req, res| !res.headers().contains_key("x-preview"))
+    ///     .build()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn should_cache(
+        mut self,
+        predicate: impl Fn(&Request, &Response) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.options.should_cache = Some(Arc::new(predicate));
+        self
+    }
+
+    /// Builds the [`Cache`], failing if no cache manager was set, or if
+    /// [`hash_keys`](CacheOptions::hash_keys) or
+    /// [`body_in_key`](CacheOptions::body_in_key) is set without the `hash-keys`
+    /// feature.
+    pub fn build(self) -> Result<Cache<T>> {
+        if (self.options.hash_keys || self.options.body_in_key) && cfg!(not(feature = "hash-keys"))
+        {
+            return Err(anyhow!(
+                "Hashing cache keys requires the `hash-keys` feature"
+            ));
+        }
+        let cache_manager = self
+            .cache_manager
+            .ok_or_else(|| anyhow!("A cache manager is required to build a Cache"))?;
+        Ok(Cache {
+            mode: self.mode,
+            cache_manager: Arc::new(cache_manager),
+            options: self.options,
+        })
+    }
 }
 
 impl<T: CacheManager> Cache<T> {
+    /// Starts building a [`Cache`], the preferred way of constructing one.
+    ///
+    /// ```no_run
+    /// # use reqwest_middleware_cache::{managers::CACacheManager, Cache, CacheMode};
+    /// # fn main() -> anyhow::Result<()> {
+    /// let cache = Cache::builder()
+    ///     .mode(CacheMode::ForceCache)
+    ///     .cache_manager(CACacheManager::default())
+    ///     .build()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn builder() -> CacheBuilder<T> {
+        CacheBuilder::default()
+    }
+}
+
+impl Cache<BoxedCacheManager> {
+    /// Creates a [`Cache`] around a manager picked at runtime, e.g. from configuration,
+    /// keeping the manager type out of the types holding the cache.
+    ///
+    /// ```no_run
+    /// # use reqwest_middleware_cache::{
+    /// #     managers::{CACacheManager, HashMapManager}, Cache, CacheManager, CacheMode,
+    /// # };
+    /// # fn main() {
+    /// # let in_memory = true;
+    /// let manager: Box<dyn CacheManager + Send + Sync> = if in_memory {
+    ///     Box::new(HashMapManager::default())
+    /// } else {
+    ///     Box::new(CACacheManager::default())
+    /// };
+    /// let cache = Cache::boxed(CacheMode::Default, manager);
+    /// # }
+    /// ```
+    pub fn boxed(mode: CacheMode, cache_manager: Box<dyn CacheManager + Send + Sync>) -> Self {
+        Cache {
+            mode,
+            cache_manager: Arc::new(cache_manager.into()),
+            options: CacheOptions::default(),
+        }
+    }
+}
+
+impl<T: CacheManager + Send + Sync + 'static> Cache<T> {
     /// Called by the Reqwest middleware handle method when a request is made.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "cache",
+            skip_all,
+            fields(method = %req.method(), url = %req.url(), mode = tracing::field::Empty)
+        )
+    )]
     pub async fn run(
         &self,
         mut req: Request,
         next: Next<'_>,
         extensions: &mut Extensions,
     ) -> Result<Response> {
-        let is_cacheable = (req.method() == Method::GET || req.method() == Method::HEAD)
-            && self.mode != CacheMode::NoStore
-            && self.mode != CacheMode::Reload;
+        let mut mode = request_mode(extensions).unwrap_or_else(|| self.mode_for(req.url()));
+        // A request asking not to be stored bypasses the cache entirely
+        // (https://tools.ietf.org/html/rfc7234#section-5.2.1.5).
+        if CacheControl::new(req.headers()).contains("no-store") {
+            mode = CacheMode::NoStore;
+        }
+        if let Some(name) = &self.options.bypass_header {
+            if req.headers_mut().remove(name).is_some() {
+                cache_event!("bypass header present");
+                mode = CacheMode::NoStore;
+            }
+        }
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("mode", &tracing::field::debug(mode));
+        let is_cacheable = self.options.is_cacheable_method(req.method())
+            && self.options.is_keyable(&req)
+            && mode != CacheMode::NoStore
+            && mode != CacheMode::Reload;
 
         if !is_cacheable {
-            return self.remote_fetch(req, next, extensions).await;
+            call_hook(&self.options.on_miss, &req);
+            return self.remote_fetch(req, mode, next, extensions).await;
         }
 
-        if let Some(store) = self.cache_manager.get(&req).await? {
+        let _flight = match &self.options.single_flight {
+            Some(single_flight) => Some(single_flight.acquire(self.options.cache_key(&req)).await),
+            None => None,
+        };
+        if let Some(store) = self.lookup(&req).await? {
             let (mut res, policy) = store;
             if let Some(warning_code) = get_warning_code(&res) {
                 // https://tools.ietf.org/html/rfc7234#section-4.3.4
@@ -131,15 +1443,30 @@ impl<T: CacheManager> Cache<T> {
                 }
             }
 
-            match self.mode {
+            // A fresh response marked `immutable` is served as is, even when the
+            // request or mode asks for it to be revalidated
+            // (https://tools.ietf.org/html/rfc8246#section-2).
+            let now = self.now();
+            if matches!(mode, CacheMode::Default | CacheMode::NoCache)
+                && is_immutable(&res)
+                && !policy.is_stale(now)
+            {
+                cache_event!("fresh immutable hit");
+                call_hook(&self.options.on_hit, &req);
+                set_freshness(&mut res, &policy, now);
+                set_hit_or_miss(&mut res, HitOrMiss::Hit);
+                return Ok(res);
+            }
+
+            match mode {
                 CacheMode::Default => Ok(self
-                    .conditional_fetch(req, res, policy, next, extensions)
+                    .conditional_fetch(req, res, policy, mode, next, extensions)
                     .await?),
                 CacheMode::NoCache => {
                     req.headers_mut()
                         .insert(CACHE_CONTROL, HeaderValue::from_str("no-cache")?);
                     Ok(self
-                        .conditional_fetch(req, res, policy, next, extensions)
+                        .conditional_fetch(req, res, policy, mode, next, extensions)
                         .await?)
                 }
                 CacheMode::ForceCache | CacheMode::OnlyIfCached => {
@@ -147,37 +1474,131 @@ impl<T: CacheManager> Cache<T> {
                     // SHOULD be included if the cache is intentionally disconnected from
                     // the rest of the network for a period of time.
                     // (https://tools.ietf.org/html/rfc2616#section-14.46)
-                    add_warning(&mut res, req.url(), 112, "Disconnected operation");
+                    self.add_warning(
+                        &mut res,
+                        req.url(),
+                        112,
+                        "Disconnected operation",
+                        self.now(),
+                    );
+                    cache_event!("cached response used without revalidation");
+                    call_hook(&self.options.on_hit, &req);
+                    set_freshness(&mut res, &policy, self.now());
+                    set_hit_or_miss(&mut res, HitOrMiss::Hit);
                     Ok(res)
                 }
-                _ => Ok(self.remote_fetch(req, next, extensions).await?),
+                _ => {
+                    call_hook(&self.options.on_miss, &req);
+                    Ok(self.remote_fetch(req, mode, next, extensions).await?)
+                }
             }
         } else {
-            match self.mode {
+            match mode {
                 CacheMode::OnlyIfCached => {
                     // ENOTCACHED
+                    cache_event!("only-if-cached gateway timeout");
+                    call_hook(&self.options.on_miss, &req);
+                    if self.options.not_cached_error {
+                        return Err(NotCached.into());
+                    }
                     let err_res = http::Response::builder()
                         .status(http::StatusCode::GATEWAY_TIMEOUT)
+                        .header(X_CACHE_LOOKUP, "MISS")
                         .body("")?;
-                    Ok(err_res.into())
+                    let mut err_res = Response::from(err_res);
+                    set_hit_or_miss(&mut err_res, HitOrMiss::Miss);
+                    Ok(err_res)
+                }
+                _ => {
+                    call_hook(&self.options.on_miss, &req);
+                    Ok(self.remote_fetch(req, mode, next, extensions).await?)
                 }
-                _ => Ok(self.remote_fetch(req, next, extensions).await?),
             }
         }
     }
 
+    /// Reports what [`Cache::run`] would do with `req` under the mode for its
+    /// host, judging the stored response by its policy without sending anything.
+    ///
+    /// ```no_run
+    /// # use reqwest::{Method, Request, Url};
+    /// # use reqwest_middleware_cache::{managers::HashMapManager, Cache, CacheDecision};
+    /// # async fn run(cache: Cache<HashMapManager>) -> anyhow::Result<()> {
+    /// let req = Request::new(Method::GET, Url::parse("https://example.com")?);
+    /// if cache.inspect(&req).await? == CacheDecision::FreshHit {
+    ///     println!("no need to go to the network");
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn inspect(&self, req: &Request) -> Result<CacheDecision> {
+        let mode = self.mode_for(req.url());
+        let bypassed = match &self.options.bypass_header {
+            Some(name) => req.headers().contains_key(name),
+            None => false,
+        };
+        if !self.options.is_cacheable_method(req.method())
+            || !self.options.is_keyable(req)
+            || matches!(mode, CacheMode::NoStore | CacheMode::Reload)
+            || CacheControl::new(req.headers()).contains("no-store")
+            || bypassed
+        {
+            return Ok(CacheDecision::Bypass);
+        }
+        let (res, policy) = match self.lookup(req).await? {
+            Some(store) => store,
+            None if mode == CacheMode::OnlyIfCached => return Ok(CacheDecision::OnlyIfCachedMiss),
+            None => return Ok(CacheDecision::Miss),
+        };
+        let now = self.now();
+        Ok(match mode {
+            CacheMode::ForceCache | CacheMode::OnlyIfCached => CacheDecision::FreshHit,
+            _ if is_immutable(&res) && !policy.is_stale(now) => CacheDecision::FreshHit,
+            CacheMode::Default => match policy.before_request(req, now) {
+                BeforeRequest::Fresh(_) => CacheDecision::FreshHit,
+                BeforeRequest::Stale { .. } => CacheDecision::StaleNeedsRevalidate,
+            },
+            _ => CacheDecision::StaleNeedsRevalidate,
+        })
+    }
+
     async fn conditional_fetch<'a>(
         &self,
         mut req: Request,
         mut cached_res: Response,
         mut policy: CachePolicy,
+        mode: CacheMode,
         next: Next<'_>,
         extensions: &mut Extensions,
     ) -> Result<Response> {
-        let before_req = policy.before_request(&req, SystemTime::now());
+        let now = self.now();
+        // Request directives (`max-age`, `min-fresh`, `max-stale`) are weighed by
+        // the policy, a stale response is only served early if the request didn't
+        // ask for freshness.
+        let req_cc = CacheControl::new(req.headers());
+        let requires_fresh = req_cc.contains("no-cache")
+            || req_cc.contains("max-age")
+            || req_cc.contains("min-fresh");
+        let before_req = policy.before_request(&req, now);
         match before_req {
             BeforeRequest::Fresh(parts) => {
                 update_response_headers(parts, &mut cached_res);
+                if mode == CacheMode::Default
+                    && self.should_refresh_ahead(&policy, now)
+                    && !requires_fresh
+                {
+                    if let (Some(client), Some(mut bg_req)) =
+                        (&self.options.revalidation_client, req.try_clone())
+                    {
+                        cache_event!("refresh ahead started in the background");
+                        add_validators(&cached_res, &mut bg_req);
+                        self.spawn_revalidation(client.clone(), bg_req);
+                    }
+                }
+                cache_event!("fresh hit");
+                call_hook(&self.options.on_hit, &req);
+                set_freshness(&mut cached_res, &policy, now);
+                set_hit_or_miss(&mut cached_res, HitOrMiss::Hit);
                 return Ok(cached_res);
             }
             BeforeRequest::Stale {
@@ -187,71 +1608,95 @@ impl<T: CacheManager> Cache<T> {
                 if matches {
                     update_request_headers(parts, &mut req);
                 }
+                // The policy leaves validators out when it doesn't consider the
+                // request a match, the server is still asked about the stored response.
+                add_validators(&cached_res, &mut req);
+                if mode == CacheMode::Default
+                    && !requires_fresh
+                    && within_stale_while_revalidate(&cached_res, &policy, now)
+                {
+                    if let (Some(client), Some(bg_req)) =
+                        (&self.options.revalidation_client, req.try_clone())
+                    {
+                        cache_event!("stale revalidation started in the background");
+                        call_hook(&self.options.on_stale, &req);
+                        self.spawn_revalidation(client.clone(), bg_req);
+                        //   110 Response is stale
+                        //   MUST be included whenever the returned response is stale.
+                        // (https://tools.ietf.org/html/rfc2616#section-14.46)
+                        self.add_warning(&mut cached_res, req.url(), 110, "Response is stale", now);
+                        set_freshness(&mut cached_res, &policy, now);
+                        set_hit_or_miss(&mut cached_res, HitOrMiss::Hit);
+                        return Ok(cached_res);
+                    }
+                    if self.options.revalidation_client.is_none() {
+                        cache_event!(
+                            "no revalidation client set, revalidating within stale-while-revalidate in the foreground"
+                        );
+                    }
+                }
             }
         }
-        let copied_req = req.try_clone().ok_or_else(|| {
-            Error::Middleware(anyhow!(
-                "Request object is not cloneable. Are you passing a streaming body?".to_string()
-            ))
-        })?;
-        match self.remote_fetch(req, next, extensions).await {
+        cache_event!("stale revalidation started");
+        let copied_req = copy_request(&req);
+        match self.remote_fetch(req, mode, next, extensions).await {
             Ok(cond_res) => {
-                if cond_res.status().is_server_error() && must_revalidate(&cached_res) {
+                if cond_res.status().is_server_error() {
+                    call_hook(&self.options.on_revalidation_failed, &copied_req);
+                    if !may_serve_stale_on_error(&cached_res, &policy, now) {
+                        return Ok(cond_res);
+                    }
+                    call_hook(&self.options.on_stale, &copied_req);
+                    cache_event!(status = %cond_res.status(), "revalidation failed, serving stale");
                     //   111 Revalidation failed
                     //   MUST be included if a cache returns a stale response
                     //   because an attempt to revalidate the response failed,
                     //   due to an inability to reach the server.
                     // (https://tools.ietf.org/html/rfc2616#section-14.46)
-                    add_warning(
+                    self.add_warning(
                         &mut cached_res,
                         copied_req.url(),
                         111,
                         "Revalidation failed",
+                        now,
                     );
+                    set_freshness(&mut cached_res, &policy, now);
+                    set_hit_or_miss(&mut cached_res, HitOrMiss::Hit);
                     Ok(cached_res)
                 } else if cond_res.status() == http::StatusCode::NOT_MODIFIED {
-                    let mut res = http::Response::builder()
-                        .status(cond_res.status())
-                        .body(cached_res.text().await?)?;
-                    for (key, value) in cond_res.headers() {
-                        res.headers_mut().append(key, value.clone());
-                    }
-                    let mut converted = Response::from(res);
-                    let after_res =
-                        policy.after_response(&copied_req, &cond_res, SystemTime::now());
-                    match after_res {
-                        AfterResponse::Modified(new_policy, parts) => {
-                            policy = new_policy;
-                            update_response_headers(parts, &mut converted);
-                        }
-                        AfterResponse::NotModified(new_policy, parts) => {
-                            policy = new_policy;
-                            update_response_headers(parts, &mut converted);
-                        }
-                    }
-                    let res = self
-                        .cache_manager
-                        .put(&copied_req, converted, policy)
+                    cache_event!("304 not modified");
+                    call_hook(&self.options.on_revalidated, &copied_req);
+                    let mut res = self
+                        .merge_not_modified(&copied_req, cached_res, &cond_res, policy)
                         .await?;
+                    set_hit_or_miss(&mut res, HitOrMiss::Revalidated);
                     Ok(res)
                 } else {
-                    Ok(cached_res)
+                    // The server sent a new response, already stored in place of
+                    // the stale one.
+                    cache_event!(status = %cond_res.status(), "stale response replaced");
+                    call_hook(&self.options.on_miss, &copied_req);
+                    Ok(cond_res)
                 }
             }
             Err(e) => {
-                if must_revalidate(&cached_res) {
+                call_hook(&self.options.on_revalidation_failed, &copied_req);
+                if !may_serve_stale_on_error(&cached_res, &policy, now) {
                     Err(e)
                 } else {
+                    cache_event!(error = %e, "revalidation failed, serving stale");
+                    call_hook(&self.options.on_stale, &copied_req);
                     //   111 Revalidation failed
                     //   MUST be included if a cache returns a stale response
                     //   because an attempt to revalidate the response failed,
                     //   due to an inability to reach the server.
                     // (https://tools.ietf.org/html/rfc2616#section-14.46)
-                    add_warning(
+                    self.add_warning(
                         &mut cached_res,
                         copied_req.url(),
                         111,
                         "Revalidation failed",
+                        now,
                     );
                     //   199 Miscellaneous warning
                     //   The warning text MAY include arbitrary information to
@@ -259,12 +1704,15 @@ impl<T: CacheManager> Cache<T> {
                     //   receiving this warning MUST NOT take any automated
                     //   action, besides presenting the warning to the user.
                     // (https://tools.ietf.org/html/rfc2616#section-14.46)
-                    add_warning(
+                    self.add_warning(
                         &mut cached_res,
                         copied_req.url(),
                         199,
                         format!("Miscellaneous Warning {}", e).as_str(),
+                        now,
                     );
+                    set_freshness(&mut cached_res, &policy, now);
+                    set_hit_or_miss(&mut cached_res, HitOrMiss::Hit);
                     Ok(cached_res)
                 }
             }
@@ -274,54 +1722,947 @@ impl<T: CacheManager> Cache<T> {
     async fn remote_fetch<'a>(
         &'a self,
         req: Request,
+        mode: CacheMode,
         next: Next<'a>,
         extensions: &mut Extensions,
     ) -> Result<Response> {
-        let copied_req = req.try_clone().ok_or_else(|| {
-            Error::Middleware(anyhow!(
-                "Request object is not clonable. Are you passing a streaming body?".to_string()
-            ))
-        })?;
+        let copied_req = copy_request(&req);
         let res = next.run(req, extensions).await?;
-        let is_method_get_head =
-            copied_req.method() == Method::GET || copied_req.method() == Method::HEAD;
-        let policy = CachePolicy::new(&copied_req, &res);
-        let is_cacheable = self.mode != CacheMode::NoStore
-            && is_method_get_head
+        let mut res = self.store_fetched(&copied_req, res, mode).await?;
+        set_hit_or_miss(&mut res, HitOrMiss::Miss);
+        Ok(res)
+    }
+
+    /// Stores a response fetched from the server if it may be cached, or drops
+    /// the record for its key when the request method invalidates it.
+    async fn store_fetched(
+        &self,
+        req: &Request,
+        res: Response,
+        mode: CacheMode,
+    ) -> Result<Response> {
+        if self.options.read_only {
+            return Ok(res);
+        }
+        let is_method_cacheable = self.options.is_cacheable_method(req.method());
+        let policy = self.response_policy(req, &res)?;
+        if mode != CacheMode::NoStore
+            && req.method() == Method::HEAD
             && res.status() == http::StatusCode::OK
-            && policy.is_storable();
+        {
+            self.update_from_head(req, &res).await?;
+        }
+        let is_cacheable = mode != CacheMode::NoStore
+            && is_method_cacheable
+            && self.options.is_keyable(req)
+            && (is_cacheable_status(res.status())
+                || self.options.is_negatively_cached(res.status()))
+            && policy.is_storable()
+            && !varies_on_everything(&res)
+            && !(self.set_cookie_mode() == SetCookieMode::Refuse
+                && res.headers().contains_key(SET_COOKIE))
+            && !(self.options.surrogate_control
+                && CacheControl::surrogate(res.headers()).contains("no-store"))
+            && self
+                .options
+                .should_cache
+                .as_ref()
+                .map_or(true, |should_cache| should_cache(req, &res));
         if is_cacheable {
-            Ok(self.cache_manager.put(&copied_req, res, policy).await?)
-        } else if !is_method_get_head {
-            self.cache_manager.delete(&copied_req).await?;
+            // Responses to `HEAD` have no body, whatever the server sent
+            // (https://tools.ietf.org/html/rfc7231#section-4.3.2).
+            let mut res = if req.method() == Method::HEAD {
+                without_response_body(res)?
+            } else {
+                res
+            };
+            let stripped = strip_headers(res.headers_mut(), &self.options.stripped_headers);
+            let cookies = match self.set_cookie_mode() {
+                SetCookieMode::Strip => take_set_cookies(&mut res),
+                _ => Vec::new(),
+            };
+            // Without these headers, the policy doesn't hand them back on later hits.
+            let policy = if cookies.is_empty() && !stripped {
+                policy
+            } else {
+                self.response_policy(req, &res)?
+            };
+            let res = match self.options.max_body_bytes {
+                Some(max) => match fits_body_limit(res, max).await? {
+                    (res, true) => res,
+                    (mut res, false) => {
+                        cache_event!(max, "response body too large to store");
+                        restore_set_cookies(&mut res, cookies);
+                        return Ok(res);
+                    }
+                },
+                None => res,
+            };
+            cache_event!(status = %res.status(), "network miss stored");
+            let mut res = self.store(req, res, policy).await?;
+            restore_set_cookies(&mut res, cookies);
+            Ok(res)
+        } else if !is_method_cacheable {
+            cache_event!(method = %req.method(), "cached record invalidated");
+            self.delete_record(&self.options.cache_key(req)).await?;
+            // A successful unsafe request invalidates the responses stored for
+            // its URL, and for same-origin URLs named by `Location` and
+            // `Content-Location` (https://tools.ietf.org/html/rfc7234#section-4.4).
+            if !req.method().is_safe() && !is_error_status(res.status()) {
+                self.invalidate_url(req, req.url()).await?;
+                for name in &[LOCATION, CONTENT_LOCATION] {
+                    let location = res
+                        .headers()
+                        .get(name)
+                        .and_then(|val| val.to_str().ok())
+                        .and_then(|val| req.url().join(val).ok());
+                    if let Some(location) = location {
+                        if location.origin() == req.url().origin() {
+                            self.invalidate_url(req, &location).await?;
+                        }
+                    }
+                }
+            }
             Ok(res)
         } else {
             Ok(res)
         }
     }
+
+    /// Removes the `GET` and `HEAD` responses stored for `url`, keyed as if
+    /// requested with the headers of `req`.
+    async fn invalidate_url(&self, req: &Request, url: &Url) -> Result<()> {
+        for method in &[Method::GET, Method::HEAD] {
+            let mut stored_req = Request::new(method.clone(), url.clone());
+            *stored_req.headers_mut() = req.headers().clone();
+            self.delete_record(&self.options.cache_key(&stored_req))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Builds the policy for a response fetched from the server, applying
+    /// [`CacheOptions::default_ttl`] when the response says nothing about freshness.
+    fn response_policy(&self, req: &Request, res: &Response) -> Result<CachePolicy> {
+        // `must-understand` lets caches knowing the caching rules of the status
+        // code store the response despite `no-store`, which keeps it out of older
+        // caches (https://www.rfc-editor.org/rfc/rfc9111#section-5.2.2.3). Only
+        // statuses cacheable by default are understood, others are never stored,
+        // whatever `override_ttl` says.
+        let cc = CacheControl::new(res.headers());
+        if cc.contains("must-understand") && cc.contains("no-store") {
+            if !is_cacheable_status(res.status()) {
+                return Ok(self.new_policy(req, res));
+            }
+            return self.response_policy(req, &without_no_store(res)?);
+        }
+        if let Some(override_ttl) = &self.options.override_ttl {
+            if let Some(ttl) = override_ttl(req, res) {
+                return Ok(self.new_policy(req, &with_default_ttl(res, ttl)?));
+            }
+        }
+        if self.options.surrogate_control {
+            if let Some(secs) = CacheControl::surrogate(res.headers()).seconds("max-age") {
+                let ttl = Duration::from_secs(secs);
+                return Ok(self.new_policy(req, &with_default_ttl(res, ttl)?));
+            }
+        }
+        if let Some(ttl) = self.options.negative_ttl {
+            if self.options.is_negatively_cached(res.status())
+                && matches!(*req.method(), Method::GET | Method::HEAD)
+                && !res.headers().contains_key(CACHE_CONTROL)
+                && !res.headers().contains_key(EXPIRES)
+            {
+                let ttl = retry_after(res, self.now()).unwrap_or(ttl);
+                // Judged as a `200` would be, since the policy doesn't consider
+                // server errors storable whatever their headers.
+                let mut synthetic = with_default_ttl(res, ttl)?;
+                *synthetic.status_mut() = http::StatusCode::OK;
+                return Ok(self.new_policy(req, &synthetic));
+            }
+        }
+        match self.options.default_ttl {
+            Some(ttl) if lacks_freshness_info(req, res) => {
+                Ok(self.new_policy(req, &with_default_ttl(res, ttl)?))
+            }
+            _ => Ok(self.new_policy(req, res)),
+        }
+    }
+
+    /// Same as `CachePolicy::new`, with the response received now as told by the
+    /// [`Clock`], judged as a shared or private cache per [`CacheOptions::shared`]
+    /// and built with [`CacheOptions::policy_options`].
+    fn new_policy(&self, req: &Request, res: &Response) -> CachePolicy {
+        let options = CachePolicyOptions {
+            shared: self.options.shared,
+            ..self.options.policy_options
+        };
+        CachePolicy::new_options(req, res, self.now(), options)
+    }
+
+    /// Freshens the stored response to the `GET` counterpart of a `HEAD` request
+    /// with the headers of its response, keeping the stored body. Only done when
+    /// the validators of both responses match
+    /// (https://tools.ietf.org/html/rfc7234#section-4.3.5).
+    async fn update_from_head(&self, req: &Request, head_res: &Response) -> Result<()> {
+        let mut get_req = Request::new(Method::GET, req.url().clone());
+        *get_req.headers_mut() = req.headers().clone();
+        let (stored, _) = match self.lookup(&get_req).await? {
+            Some(stored) => stored,
+            None => return Ok(()),
+        };
+        if !validators_match(&stored, head_res) {
+            return Ok(());
+        }
+        cache_event!("stored GET response updated from HEAD");
+        let status = stored.status();
+        let version = stored.version();
+        let mut headers = stored.headers().clone();
+        for name in head_res.headers().keys() {
+            // The stored body keeps its own length.
+            if name == CONTENT_LENGTH {
+                continue;
+            }
+            headers.remove(name);
+            for value in head_res.headers().get_all(name) {
+                headers.append(name.clone(), value.clone());
+            }
+        }
+        let mut res = http::Response::new(stored.bytes().await?);
+        *res.status_mut() = status;
+        *res.version_mut() = version;
+        *res.headers_mut() = headers;
+        let res = Response::from(res);
+        let policy = self.response_policy(&get_req, &res)?;
+        self.store(&get_req, res, policy).await?;
+        Ok(())
+    }
+
+    /// Combines the stored response with the headers of a `304 Not Modified`
+    /// and writes the new headers back to the cache.
+    async fn merge_not_modified(
+        &self,
+        req: &Request,
+        mut cached_res: Response,
+        cond_res: &Response,
+        policy: CachePolicy,
+    ) -> Result<Response> {
+        // The revalidated response is served as the stored one, so it keeps the
+        // stored status and body rather than those of the 304.
+        let (policy, parts) = match policy.after_response(req, cond_res, self.now()) {
+            AfterResponse::NotModified(new_policy, parts) => (new_policy, parts),
+            AfterResponse::Modified(new_policy, parts) => {
+                cache_event!("304 does not match the stored response");
+                (new_policy, parts)
+            }
+        };
+        *cached_res.headers_mut() = cond_res.headers().clone();
+        update_response_headers(parts, &mut cached_res);
+        strip_headers(cached_res.headers_mut(), &self.options.stripped_headers);
+        let mut res = self
+            .update_stored_metadata(req, cached_res, policy.clone())
+            .await?;
+        set_freshness(&mut res, &policy, self.now());
+        Ok(res)
+    }
+
+    /// Revalidates a response that has already been served stale, without
+    /// holding up the caller. Failures leave the stored response untouched.
+    fn spawn_revalidation(&self, client: Client, req: Request) {
+        let cache = self.clone();
+        tokio::spawn(async move {
+            if let Err(_e) = cache.revalidate(client, req).await {
+                cache_event!(error = %_e, "background revalidation failed");
+            }
+        });
+    }
+
+    async fn revalidate(&self, client: Client, req: Request) -> Result<()> {
+        let copied_req = copy_request(&req);
+        let cond_res = match client.execute(req).await {
+            Ok(cond_res) => cond_res,
+            Err(e) => {
+                call_hook(&self.options.on_revalidation_failed, &copied_req);
+                return Err(e.into());
+            }
+        };
+        if cond_res.status() == http::StatusCode::NOT_MODIFIED {
+            call_hook(&self.options.on_revalidated, &copied_req);
+            if let Some((cached_res, policy)) = self.lookup(&copied_req).await? {
+                self.merge_not_modified(&copied_req, cached_res, &cond_res, policy)
+                    .await?;
+            }
+        } else if cond_res.status().is_server_error() {
+            call_hook(&self.options.on_revalidation_failed, &copied_req);
+        } else {
+            self.store_fetched(&copied_req, cond_res, CacheMode::Default)
+                .await?;
+        }
+        Ok(())
+    }
+
+    fn now(&self) -> SystemTime {
+        self.options.clock.now()
+    }
+
+    /// The mode set for the host of `url` in [`CacheOptions::host_modes`], or
+    /// [`Cache::mode`].
+    fn mode_for(&self, url: &Url) -> CacheMode {
+        url.host_str()
+            .and_then(|host| self.options.host_modes.get(&host.to_ascii_lowercase()))
+            .copied()
+            .unwrap_or(self.mode)
+    }
+
+    fn set_cookie_mode(&self) -> SetCookieMode {
+        if self.options.shared {
+            self.options.set_cookie
+        } else {
+            SetCookieMode::Store
+        }
+    }
+
+    /// Whether a fresh response has been stored long enough to be refreshed
+    /// ahead of going stale, see [`CacheOptions::refresh_ahead`].
+    fn should_refresh_ahead(&self, policy: &CachePolicy, now: SystemTime) -> bool {
+        let threshold = match self.options.refresh_ahead {
+            Some(threshold) => threshold,
+            None => return false,
+        };
+        let age = policy.age(now);
+        let lifetime = age + policy.time_to_live(now);
+        !lifetime.is_zero() && age.as_secs_f64() >= lifetime.as_secs_f64() * f64::from(threshold)
+    }
+
+    /// Adds a `Warning` header to a response served for `url`, unless disabled
+    /// with [`CacheOptions::warnings`].
+    fn add_warning(
+        &self,
+        res: &mut Response,
+        url: &Url,
+        code: usize,
+        message: &str,
+        date: SystemTime,
+    ) {
+        if self.options.warnings {
+            let agent = warn_agent(self.options.warn_agent.as_deref(), url);
+            add_warning(res, agent, code, message, date);
+        }
+    }
+
+    /// Reads the record for a request, following the primary record to the
+    /// variant matching the request when the stored response has a `Vary` header.
+    async fn lookup(&self, req: &Request) -> Result<Option<(Response, CachePolicy)>> {
+        let key = self.options.cache_key(req);
+        match self.get_record(&key).await? {
+            Some((res, policy)) => match vary_header_names(&res) {
+                Some(names) => self.get_record(&vary_key(&key, req, &names)).await,
+                None => Ok(Some((res, policy))),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Writes the record for a request. Responses carrying a `Vary` header are
+    /// stored under a key including the varying request headers, and the primary
+    /// key is left pointing at them with a bodiless record holding only `Vary`.
+    /// The pointer is stored with the policy of its longest lived variant, so
+    /// managers dropping stale records don't strand the variants behind it.
+    async fn store(&self, req: &Request, res: Response, policy: CachePolicy) -> Result<Response> {
+        if self.options.read_only {
+            return Ok(res);
+        }
+        let key = self.options.cache_key(req);
+        match vary_header_names(&res) {
+            Some(names) => {
+                let mut pointer = http::Response::builder().status(res.status());
+                for value in res.headers().get_all(VARY) {
+                    pointer = pointer.header(VARY, value.clone());
+                }
+                let pointer = Response::from(pointer.body(Vec::new())?);
+                let now = self.now();
+                let pointer_policy = match self.get_policy_record(&key).await? {
+                    Some(stored) if stored.time_to_live(now) > policy.time_to_live(now) => stored,
+                    _ => policy.clone(),
+                };
+                self.put_record(&key, pointer, pointer_policy).await?;
+                self.put_record(&vary_key(&key, req, &names), res, policy)
+                    .await
+            }
+            None => self.put_record(&key, res, policy).await,
+        }
+    }
+
+    /// Rewrites the headers and policy stored for a request, leaving the body and
+    /// any `Vary` pointer untouched.
+    async fn update_stored_metadata(
+        &self,
+        req: &Request,
+        res: Response,
+        policy: CachePolicy,
+    ) -> Result<Response> {
+        if self.options.read_only {
+            return Ok(res);
+        }
+        let mut key = self.options.cache_key(req);
+        if let Some(names) = vary_header_names(&res) {
+            key = vary_key(&key, req, &names);
+        }
+        if !self.options.fail_open {
+            return self
+                .cache_manager
+                .update_metadata(&key, res, policy)
+                .await
+                .map_err(backend_error);
+        }
+        // Unlike with `put_record` the body is left unbuffered, should the update
+        // fail it's read back from the cache under the new headers.
+        let status = res.status();
+        let version = res.version();
+        let headers = res.headers().clone();
+        let e = match self.cache_manager.update_metadata(&key, res, policy).await {
+            Ok(res) => return Ok(res),
+            Err(e) => e,
+        };
+        cache_event!(key = key.as_str(), error = %e, "cache update failed, serving without storing");
+        let stored = match self.get_record(&key).await? {
+            Some((stored, _)) => stored,
+            None => return Err(backend_error(e)),
+        };
+        let mut res = http::Response::builder()
+            .status(status)
+            .version(version)
+            .url(stored.url().clone())
+            .body(reqwest::Body::from(stored))?;
+        *res.headers_mut() = headers;
+        Ok(Response::from(res))
+    }
+
+    // The cache manager calls, with errors swallowed when `fail_open` is set.
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    async fn get_record(&self, key: &str) -> Result<Option<(Response, CachePolicy)>> {
+        match self.cache_manager.get(key).await {
+            Err(_e) if self.options.fail_open => {
+                cache_event!(key, error = %_e, "cache read failed, treating as a miss");
+                Ok(None)
+            }
+            result => result.map_err(backend_error),
+        }
+    }
+
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    async fn get_policy_record(&self, key: &str) -> Result<Option<CachePolicy>> {
+        match self.cache_manager.get_policy(key).await {
+            Err(_e) if self.options.fail_open => {
+                cache_event!(key, error = %_e, "cache read failed, treating as a miss");
+                Ok(None)
+            }
+            result => result.map_err(backend_error),
+        }
+    }
+
+    async fn put_record(&self, key: &str, res: Response, policy: CachePolicy) -> Result<Response> {
+        if !self.options.fail_open {
+            return self
+                .cache_manager
+                .put(key, res, policy)
+                .await
+                .map_err(backend_error);
+        }
+        // The manager consumes the response it is given, keep a copy to serve
+        // in case it fails.
+        let (res, copy) = duplicate_response(res).await?;
+        match self.cache_manager.put(key, copy, policy).await {
+            Ok(res) => Ok(res),
+            Err(_e) => {
+                cache_event!(key, error = %_e, "cache write failed, serving without storing");
+                Ok(res)
+            }
+        }
+    }
+
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    async fn delete_record(&self, key: &str) -> Result<()> {
+        match self.cache_manager.delete(key).await {
+            Err(_e) if self.options.fail_open => {
+                cache_event!(key, error = %_e, "cache delete failed, ignoring");
+                Ok(())
+            }
+            result => result.map_err(backend_error),
+        }
+    }
 }
 
-fn must_revalidate(res: &Response) -> bool {
-    if let Some(val) = res.headers().get(CACHE_CONTROL.as_str()) {
-        val.to_str()
-            .expect("Unable to convert header value to string")
-            .to_lowercase()
-            .contains("must-revalidate")
+// Wraps an error from the cache manager as a `CacheError::Backend`, unless the
+// manager already said what kind of error it is.
+fn backend_error(e: anyhow::Error) -> anyhow::Error {
+    if e.is::<CacheError>() {
+        e
     } else {
-        false
+        CacheError::Backend(e).into()
     }
 }
 
-fn get_warning_code(res: &Response) -> Option<usize> {
-    res.headers().get(reqwest::header::WARNING).and_then(|hdr| {
-        hdr.to_str()
-            .expect("Unable to convert warning to string")
-            .chars()
-            .take(3)
-            .collect::<String>()
-            .parse()
+// Sets `Age` to how long ago the stored response was generated, including any
+// age reported by the server (https://tools.ietf.org/html/rfc7234#section-5.1),
+// and records the rest of its freshness in the extensions.
+fn set_freshness(res: &mut Response, policy: &CachePolicy, now: SystemTime) {
+    let freshness = CacheFreshness {
+        age: policy.age(now),
+        time_to_live: policy.time_to_live(now),
+        stale: policy.is_stale(now),
+    };
+    res.headers_mut()
+        .insert(AGE, HeaderValue::from(freshness.age.as_secs()));
+    res.extensions_mut().insert(freshness);
+}
+
+// A copy of the request, without its body when it's a stream that can't be
+// cloned. Enough to key the request and build policies, never to send it again.
+fn copy_request(req: &Request) -> Request {
+    if let Some(copy) = req.try_clone() {
+        return copy;
+    }
+    let mut copy = Request::new(req.method().clone(), req.url().clone());
+    *copy.headers_mut() = req.headers().clone();
+    *copy.version_mut() = req.version();
+    copy
+}
+
+fn set_hit_or_miss(res: &mut Response, status: HitOrMiss) {
+    res.extensions_mut().insert(status);
+}
+
+fn request_mode(extensions: &Extensions) -> Option<CacheMode> {
+    extensions.get::<CacheMode>().copied()
+}
+
+// Whether the URL in a key of the default `METHOD:URL` form starts with `prefix`.
+pub(crate) fn key_has_url_prefix(key: &str, prefix: &str) -> bool {
+    key.split_once(':')
+        .map_or(false, |(_, url)| url.starts_with(prefix))
+}
+
+fn req_key(req: &Request, url: &Url) -> String {
+    format!("{}:{}", req.method(), url)
+}
+
+// Characters of the URL kept readable in hashed keys.
+const HASHED_KEY_URL_LEN: usize = 64;
+
+#[cfg(feature = "hash-keys")]
+fn hashed_req_key(req: &Request, url: &Url) -> String {
+    let readable: String = url.as_str().chars().take(HASHED_KEY_URL_LEN).collect();
+    format!(
+        "{}:{}#{:x}",
+        req.method(),
+        readable,
+        Sha256::digest(url.as_str().as_bytes())
+    )
+}
+
+// Only reached by caches built without `CacheBuilder::build`, which refuses
+// `hash_keys` without the `hash-keys` feature.
+#[cfg(not(feature = "hash-keys"))]
+fn hashed_req_key(req: &Request, url: &Url) -> String {
+    req_key(req, url)
+}
+
+// The URL with its query parameters sorted and/or stripped of the ignored ones,
+// untouched when neither is asked for.
+fn normalize_url(url: &Url, sort: bool, ignored: &[String]) -> Url {
+    let mut url = url.clone();
+    if url.query().is_none() || (!sort && ignored.is_empty()) {
+        return url;
+    }
+    let mut pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(name, _)| {
+            !ignored
+                .iter()
+                .any(|pattern| query_param_matches(pattern, name))
+        })
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect();
+    if sort {
+        pairs.sort();
+    }
+    if pairs.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    url
+}
+
+// Drops an empty query and the trailing slashes of any path but the root.
+fn canonicalize_url(mut url: Url) -> Url {
+    if url.query() == Some("") {
+        url.set_query(None);
+    }
+    if url.path().len() > 1 && url.path().ends_with('/') {
+        let path = url.path().trim_end_matches('/').to_owned();
+        url.set_path(if path.is_empty() { "/" } else { &path });
+    }
+    url
+}
+
+fn query_param_matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
+
+fn vary_header_names(res: &Response) -> Option<Vec<String>> {
+    let mut names: Vec<String> = res
+        .headers()
+        .get_all(VARY)
+        .iter()
+        .filter_map(|val| val.to_str().ok())
+        .flat_map(|val| val.split(','))
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    if names.is_empty() {
+        return None;
+    }
+    names.sort();
+    names.dedup();
+    Some(names)
+}
+
+fn vary_key(key: &str, req: &Request, names: &[String]) -> String {
+    let mut vary_key = key.to_owned();
+    for name in names {
+        let values: Vec<String> = req
+            .headers()
+            .get_all(name.as_str())
+            .iter()
+            .map(|val| String::from_utf8_lossy(val.as_bytes()).into_owned())
+            .collect();
+        vary_key.push_str(&format!("|{}={}", name, values.join(",")));
+    }
+    vary_key
+}
+
+// A `Vary` header containing `*` means the response can never be matched
+// by a later request (https://tools.ietf.org/html/rfc7234#section-4.1).
+fn varies_on_everything(res: &Response) -> bool {
+    vary_header_names(res).map_or(false, |names| names.iter().any(|name| name == "*"))
+}
+
+// Status codes defined as cacheable by default
+// (https://tools.ietf.org/html/rfc7231#section-6.1), minus 206 Partial Content
+// since a stored range would later be served as if it were the full body.
+fn is_cacheable_status(status: http::StatusCode) -> bool {
+    matches!(
+        status.as_u16(),
+        200 | 203 | 204 | 300 | 301 | 404 | 405 | 410 | 414 | 501
+    )
+}
+
+// Whether the body of `res` is no larger than `max` bytes. `Content-Length` settles
+// it when present, otherwise the body is read to be measured and handed back in a
+// response rebuilt around it.
+async fn fits_body_limit(res: Response, max: usize) -> Result<(Response, bool)> {
+    if let Some(len) = res.content_length() {
+        return Ok((res, len <= max as u64));
+    }
+    let mut buffered = http::Response::builder()
+        .status(res.status())
+        .version(res.version())
+        .url(res.url().clone());
+    if let Some(headers) = buffered.headers_mut() {
+        *headers = res.headers().clone();
+    }
+    let body = res.bytes().await?;
+    let fits = body.len() <= max;
+    Ok((Response::from(buffered.body(body)?), fits))
+}
+
+// The response with an empty body, keeping everything else.
+fn without_response_body(res: Response) -> Result<Response> {
+    let mut empty = http::Response::builder()
+        .status(res.status())
+        .version(res.version())
+        .url(res.url().clone())
+        .body(Vec::new())?;
+    *empty.headers_mut() = res.headers().clone();
+    Ok(Response::from(empty))
+}
+
+// Reads the body into memory, returning two copies of the response.
+async fn duplicate_response(res: Response) -> Result<(Response, Response)> {
+    let status = res.status();
+    let version = res.version();
+    let url = res.url().clone();
+    let headers = res.headers().clone();
+    let body = res.bytes().await?;
+    let build = |body: reqwest::Body| -> Result<Response> {
+        let mut res = http::Response::builder()
+            .status(status)
+            .version(version)
+            .url(url.clone())
+            .body(body)?;
+        *res.headers_mut() = headers.clone();
+        Ok(Response::from(res))
+    };
+    Ok((build(body.clone().into())?, build(body.into())?))
+}
+
+/// Removes the named headers, and those listed by `Connection` when it is named
+/// itself. Returns whether any header was removed.
+fn strip_headers(headers: &mut HeaderMap, names: &[HeaderName]) -> bool {
+    let mut stripped = false;
+    if names.contains(&CONNECTION) {
+        let listed: Vec<HeaderName> = headers
+            .get_all(CONNECTION)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+            .collect();
+        for name in listed {
+            stripped |= headers.remove(name).is_some();
+        }
+    }
+    for name in names {
+        stripped |= headers.remove(name).is_some();
+    }
+    stripped
+}
+
+fn take_set_cookies(res: &mut Response) -> Vec<HeaderValue> {
+    let cookies = res.headers().get_all(SET_COOKIE).iter().cloned().collect();
+    res.headers_mut().remove(SET_COOKIE);
+    cookies
+}
+
+fn restore_set_cookies(res: &mut Response, cookies: Vec<HeaderValue>) {
+    for cookie in cookies {
+        res.headers_mut().append(SET_COOKIE, cookie);
+    }
+}
+
+fn is_error_status(status: http::StatusCode) -> bool {
+    status.is_client_error() || status.is_server_error()
+}
+
+// The delay given by `Retry-After`, in seconds or as a date
+// (https://tools.ietf.org/html/rfc7231#section-7.1.3).
+fn retry_after(res: &Response, now: SystemTime) -> Option<Duration> {
+    let value = res.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    match value.parse::<u64>() {
+        Ok(secs) => Some(Duration::from_secs(secs)),
+        Err(_) => httpdate::parse_http_date(value)
             .ok()
-    })
+            .map(|date| date.duration_since(now).unwrap_or_default()),
+    }
+}
+
+fn lacks_freshness_info(req: &Request, res: &Response) -> bool {
+    matches!(*req.method(), Method::GET | Method::HEAD)
+        && res.status() == http::StatusCode::OK
+        && !res.headers().contains_key(CACHE_CONTROL)
+        && !res.headers().contains_key(EXPIRES)
+}
+
+// A bodiless copy of the response as if it had been sent with `max-age` set to
+// `ttl`, to build its policy from while leaving the response itself untouched.
+fn with_default_ttl(res: &Response, ttl: Duration) -> Result<Response> {
+    let mut synthetic = http::Response::builder()
+        .status(res.status())
+        .version(res.version())
+        .body(Vec::new())?;
+    *synthetic.headers_mut() = res.headers().clone();
+    synthetic.headers_mut().insert(
+        CACHE_CONTROL,
+        HeaderValue::from_str(&format!("max-age={}", ttl.as_secs()))?,
+    );
+    Ok(Response::from(synthetic))
+}
+
+// A bodiless copy of the response with `no-store` left out of its `Cache-Control`,
+// to build its policy from.
+fn without_no_store(res: &Response) -> Result<Response> {
+    let mut synthetic = http::Response::builder()
+        .status(res.status())
+        .version(res.version())
+        .body(Vec::new())?;
+    *synthetic.headers_mut() = res.headers().clone();
+    synthetic.headers_mut().remove(CACHE_CONTROL);
+    for val in res.headers().get_all(CACHE_CONTROL) {
+        let val = match val.to_str() {
+            Ok(val) => val,
+            Err(_) => continue,
+        };
+        let kept: Vec<&str> = split_directives(val)
+            .into_iter()
+            .filter(|part| !part.trim().eq_ignore_ascii_case("no-store"))
+            .collect();
+        synthetic
+            .headers_mut()
+            .append(CACHE_CONTROL, HeaderValue::from_str(&kept.join(","))?);
+    }
+    Ok(Response::from(synthetic))
+}
+
+// Whether two responses carry the same strong or weak `ETag`, or failing that,
+// the same `Last-Modified`. Responses without either never match.
+fn validators_match(stored: &Response, res: &Response) -> bool {
+    let stored = stored.headers();
+    let res = res.headers();
+    match (stored.get(ETAG), res.get(ETAG)) {
+        (Some(a), Some(b)) => opaque_tag(a) == opaque_tag(b),
+        (None, None) => match (stored.get(LAST_MODIFIED), res.get(LAST_MODIFIED)) {
+            (Some(a), Some(b)) => a == b,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+// An `ETag` without its weakness indicator, for weak comparison.
+fn opaque_tag(etag: &HeaderValue) -> &[u8] {
+    let etag = etag.as_bytes();
+    etag.strip_prefix(b"W/").unwrap_or(etag)
+}
+
+// The directives of every `Cache-Control` line in a header map, folded together.
+// Names are lowercased and quotes are removed from values, lines that aren't
+// valid UTF-8 are skipped.
+struct CacheControl {
+    directives: Vec<(String, Option<String>)>,
+}
+
+impl CacheControl {
+    fn new(headers: &HeaderMap) -> Self {
+        CacheControl::parse(headers, CACHE_CONTROL)
+    }
+
+    // `Surrogate-Control`, which uses the same syntax
+    // (https://www.w3.org/TR/edge-arch/).
+    fn surrogate(headers: &HeaderMap) -> Self {
+        CacheControl::parse(headers, HeaderName::from_static("surrogate-control"))
+    }
+
+    fn parse(headers: &HeaderMap, name: HeaderName) -> Self {
+        let mut directives = Vec::new();
+        for val in headers.get_all(name) {
+            let val = match val.to_str() {
+                Ok(val) => val,
+                Err(_) => continue,
+            };
+            for part in split_directives(val) {
+                let mut split = part.splitn(2, '=');
+                let name = split.next().unwrap_or_default().trim().to_ascii_lowercase();
+                if name.is_empty() {
+                    continue;
+                }
+                let value = split
+                    .next()
+                    .map(|val| val.trim().trim_matches('"').to_owned());
+                directives.push((name, value));
+            }
+        }
+        CacheControl { directives }
+    }
+
+    fn contains(&self, directive: &str) -> bool {
+        self.directives.iter().any(|(name, _)| name == directive)
+    }
+
+    // Value of a directive carrying a number of seconds, e.g. `max-age=60`.
+    fn seconds(&self, directive: &str) -> Option<u64> {
+        self.directives
+            .iter()
+            .find(|(name, _)| name == directive)
+            .and_then(|(_, value)| value.as_ref()?.parse().ok())
+    }
+}
+
+// Splits on the commas that aren't part of a quoted value, as in `no-cache="a, b"`.
+fn split_directives(val: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    for (i, c) in val.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                parts.push(&val[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&val[start..]);
+    parts
+}
+
+// Whether the response has been stale for less than the number of seconds
+// given by `directive`, `None` when the response doesn't carry it.
+fn stale_within(
+    res: &Response,
+    policy: &CachePolicy,
+    now: SystemTime,
+    directive: &str,
+) -> Option<bool> {
+    let window = Duration::from_secs(CacheControl::new(res.headers()).seconds(directive)?);
+    // Still fresh `window` ago means no more than `window` stale now.
+    Some(
+        now.checked_sub(window)
+            .map_or(false, |then| policy.time_to_live(then) > Duration::ZERO),
+    )
+}
+
+// A stale response may be served while it's revalidated in the background for
+// as long as it has been stale for less than its `stale-while-revalidate` window
+// (https://tools.ietf.org/html/rfc5861#section-3).
+fn within_stale_while_revalidate(res: &Response, policy: &CachePolicy, now: SystemTime) -> bool {
+    !must_revalidate(res)
+        && stale_within(res, policy, now, "stale-while-revalidate").unwrap_or(false)
+}
+
+// A stale response may stand in for a server error or a failed request, bounded
+// by its `stale-if-error` window when it has one
+// (https://tools.ietf.org/html/rfc5861#section-4).
+fn may_serve_stale_on_error(res: &Response, policy: &CachePolicy, now: SystemTime) -> bool {
+    !must_revalidate(res) && stale_within(res, policy, now, "stale-if-error").unwrap_or(true)
+}
+
+// `proxy-revalidate` only binds shared caches, it is honored by private ones too
+// as the cautious choice.
+fn must_revalidate(res: &Response) -> bool {
+    let cache_control = CacheControl::new(res.headers());
+    cache_control.contains("must-revalidate") || cache_control.contains("proxy-revalidate")
+}
+
+fn is_immutable(res: &Response) -> bool {
+    CacheControl::new(res.headers()).contains("immutable")
+}
+
+// Reads the code off the raw bytes, the rest of a stored `Warning` may be in any
+// encoding the origin chose.
+fn get_warning_code(res: &Response) -> Option<usize> {
+    let hdr = res.headers().get(reqwest::header::WARNING)?;
+    std::str::from_utf8(hdr.as_bytes().get(..3)?)
+        .ok()?
+        .parse()
+        .ok()
+}
+
+// Adds `If-None-Match` and `If-Modified-Since` for the stored response's `ETag` and
+// `Last-Modified`, leaving any the request already carries alone.
+fn add_validators(stored: &Response, req: &mut Request) {
+    for (validator, condition) in &[(ETAG, IF_NONE_MATCH), (LAST_MODIFIED, IF_MODIFIED_SINCE)] {
+        if let Some(value) = stored.headers().get(validator) {
+            if !req.headers().contains_key(condition) {
+                req.headers_mut().insert(condition.clone(), value.clone());
+            }
+        }
+    }
 }
 
 fn update_request_headers(parts: http::request::Parts, req: &mut Request) {
@@ -331,13 +2672,32 @@ fn update_request_headers(parts: http::request::Parts, req: &mut Request) {
     }
 }
 
+// Replaces the headers named in `parts` with every value they have there, so
+// repeated headers like `Set-Cookie` aren't collapsed to their last value.
 fn update_response_headers(parts: http::response::Parts, res: &mut Response) {
-    for header in parts.headers.iter() {
-        res.headers_mut().insert(header.0.clone(), header.1.clone());
+    let mut last = None;
+    for (name, value) in parts.headers {
+        match name {
+            Some(name) => {
+                res.headers_mut().insert(&name, value);
+                last = Some(name);
+            }
+            None => {
+                if let Some(name) = &last {
+                    res.headers_mut().append(name, value);
+                }
+            }
+        }
     }
 }
 
-fn add_warning(res: &mut Response, uri: &reqwest::Url, code: usize, message: &str) {
+// The configured pseudonym, or the host the request was sent to, falling back to
+// `-` for URLs without one.
+fn warn_agent<'a>(pseudonym: Option<&'a str>, uri: &'a Url) -> &'a str {
+    pseudonym.unwrap_or_else(|| uri.host_str().unwrap_or("-"))
+}
+
+fn add_warning(res: &mut Response, agent: &str, code: usize, message: &str, date: SystemTime) {
     //   Warning    = "Warning" ":" 1#warning-value
     // warning-value = warn-code SP warn-agent SP warn-text [SP warn-date]
     // warn-code  = 3DIGIT
@@ -348,22 +2708,24 @@ fn add_warning(res: &mut Response, uri: &reqwest::Url, code: usize, message: &st
     // warn-date  = <"> HTTP-date <">
     // (https://tools.ietf.org/html/rfc2616#section-14.46)
     //
-    let val = HeaderValue::from_str(
+    // Warnings are best-effort, a value that can't be turned into a header is
+    // simply not added.
+    if let Ok(val) = HeaderValue::from_str(
         format!(
             "{} {} {:?} \"{}\"",
             code,
-            uri.host().expect("Invalid URL"),
+            agent,
             message,
-            httpdate::fmt_http_date(SystemTime::now())
+            httpdate::fmt_http_date(date)
         )
         .as_str(),
-    )
-    .expect("Failed to generate warning string");
-    res.headers_mut().append(reqwest::header::WARNING, val);
+    ) {
+        res.headers_mut().append(reqwest::header::WARNING, val);
+    }
 }
 
 #[async_trait::async_trait]
-impl<T: CacheManager + 'static + Send + Sync> Middleware for Cache<T> {
+impl<T: CacheManager + Send + Sync + 'static> Middleware for Cache<T> {
     async fn handle(
         &self,
         req: Request,
@@ -375,6 +2737,45 @@ impl<T: CacheManager + 'static + Send + Sync> Middleware for Cache<T> {
     }
 }
 
+/// Fetches each of `urls` with a `GET` through `client`, which should have the
+/// [`Cache`] middleware installed, so the responses are stored ahead of the first
+/// real request. Requests are sent one after the other as [`CacheMode::Reload`],
+/// returning the status or error for each URL in order.
+///
+/// ```no_run
+/// # use reqwest_middleware::ClientWithMiddleware;
+/// # async fn run(client: ClientWithMiddleware) {
+/// let urls = ["https://example.com/a", "https://example.com/b"];
+/// for (url, result) in reqwest_middleware_cache::warm(&client, &urls).await {
+///     if let Err(e) = result {
+///         eprintln!("failed to warm {}: {}", url, e);
+///     }
+/// }
+/// # }
+/// ```
+pub async fn warm<I>(
+    client: &ClientWithMiddleware,
+    urls: I,
+) -> Vec<(String, reqwest_middleware::Result<http::StatusCode>)>
+where
+    I: IntoIterator,
+    I::Item: AsRef<str>,
+{
+    let mut results = Vec::new();
+    for url in urls {
+        let url = url.as_ref().to_owned();
+        let mut extensions = Extensions::new();
+        extensions.insert(CacheMode::Reload);
+        let result = client
+            .get(&url)
+            .send_with_extensions(&mut extensions)
+            .await
+            .map(|res| res.status());
+        results.push((url, result));
+    }
+    results
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -386,12 +2787,74 @@ mod tests {
     async fn can_get_warning_code() -> Result<()> {
         let url = reqwest::Url::from_str("https://example.com")?;
         let mut res = reqwest::Response::from(Response::new(""));
-        add_warning(&mut res, &url, 111, "Revalidation failed");
+        add_warning(
+            &mut res,
+            warn_agent(None, &url),
+            111,
+            "Revalidation failed",
+            SystemTime::now(),
+        );
         let code = get_warning_code(&res).unwrap();
         assert_eq!(code, 111);
         Ok(())
     }
 
+    #[test]
+    fn can_get_warning_code_from_non_ascii_warning() -> Result<()> {
+        let mut res = reqwest::Response::from(Response::new(""));
+        res.headers_mut().insert(
+            reqwest::header::WARNING,
+            HeaderValue::from_bytes(b"199 - \"d\xe9j\xe0 vu\"")?,
+        );
+        assert_eq!(get_warning_code(&res), Some(199));
+        res.headers_mut().insert(
+            reqwest::header::WARNING,
+            HeaderValue::from_bytes(b"\xe9\xe9 - \"oops\"")?,
+        );
+        assert_eq!(get_warning_code(&res), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_add_warning_without_host() -> Result<()> {
+        let url = reqwest::Url::from_str("data:text/plain,test")?;
+        let mut res = reqwest::Response::from(Response::new(""));
+        add_warning(
+            &mut res,
+            warn_agent(None, &url),
+            112,
+            "Disconnected operation",
+            SystemTime::now(),
+        );
+        let warning = res.headers().get(reqwest::header::WARNING).unwrap();
+        assert!(warning.to_str()?.starts_with("112 - "));
+        let mut named = reqwest::Response::from(Response::new(""));
+        add_warning(
+            &mut named,
+            warn_agent(Some("edge-cache"), &url),
+            112,
+            "Disconnected operation",
+            SystemTime::now(),
+        );
+        let warning = named.headers().get(reqwest::header::WARNING).unwrap();
+        assert!(warning.to_str()?.starts_with("112 edge-cache "));
+        add_warning(
+            &mut res,
+            warn_agent(None, &url),
+            199,
+            "Non-ASCII warn-text: café",
+            SystemTime::now(),
+        );
+        assert_eq!(
+            res.headers()
+                .get_all(reqwest::header::WARNING)
+                .iter()
+                .count(),
+            1
+        );
+        Ok(())
+    }
+
     #[tokio::test]
     async fn can_check_revalidate() -> Result<()> {
         let mut res = Response::new("");
@@ -403,4 +2866,212 @@ mod tests {
         assert!(check, "{}", true);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn can_parse_cache_control() -> Result<()> {
+        let check = |values: &[&[u8]]| -> Result<bool> {
+            let mut res = Response::new("");
+            for val in values {
+                res.headers_mut()
+                    .append("Cache-Control", HeaderValue::from_bytes(val)?);
+            }
+            Ok(must_revalidate(&res.into()))
+        };
+        assert!(!check(&[b"max-age=60, proxy-must-revalidate"])?);
+        assert!(!check(&[b"no-cache=\"must-revalidate, x\""])?);
+        assert!(!check(&[b"max-age=60, caf\xe9"])?);
+        assert!(check(&[b"max-age=60", b"Must-Revalidate"])?);
+        assert!(check(&[b"proxy-revalidate"])?);
+        let mut res = Response::new("");
+        res.headers_mut().append(
+            "Cache-Control",
+            HeaderValue::from_str("private=\"a, b\", stale-if-error=\"30\"")?,
+        );
+        res.headers_mut().append(
+            "Cache-Control",
+            HeaderValue::from_str("stale-while-revalidate=60")?,
+        );
+        let cache_control = CacheControl::new(res.headers());
+        assert_eq!(cache_control.seconds("stale-if-error"), Some(30));
+        assert_eq!(cache_control.seconds("stale-while-revalidate"), Some(60));
+        assert!(cache_control.contains("private"));
+        Ok(())
+    }
+
+    #[test]
+    fn can_debug_without_debug_manager() {
+        struct Opaque;
+
+        #[async_trait::async_trait]
+        impl CacheManager for Opaque {
+            async fn get(&self, _: &str) -> Result<Option<(reqwest::Response, CachePolicy)>> {
+                Ok(None)
+            }
+            async fn put(
+                &self,
+                _: &str,
+                res: reqwest::Response,
+                _: CachePolicy,
+            ) -> Result<reqwest::Response> {
+                Ok(res)
+            }
+            async fn delete(&self, _: &str) -> Result<()> {
+                Ok(())
+            }
+        }
+
+        let cache = Cache {
+            mode: CacheMode::Default,
+            cache_manager: Arc::new(Opaque),
+            options: CacheOptions::default(),
+        };
+        let debug = format!("{:?}", cache);
+        assert!(debug.contains("mode: Default"));
+        assert!(debug.contains("Opaque"));
+        // Neither `Debug` nor `Clone` is needed to use the manager as middleware
+        reqwest_middleware::ClientBuilder::new(Client::new())
+            .with(cache)
+            .build();
+    }
+
+    #[test]
+    fn backend_errors_keep_their_kind() {
+        let e = backend_error(anyhow!("connection refused"));
+        assert!(matches!(
+            e.downcast_ref::<CacheError>(),
+            Some(CacheError::Backend(_))
+        ));
+        let e = backend_error(CacheError::Serialization(anyhow!("bad record")).into());
+        assert!(matches!(
+            e.downcast_ref::<CacheError>(),
+            Some(CacheError::Serialization(_))
+        ));
+    }
+
+    #[cfg(feature = "hash-keys")]
+    #[test]
+    fn can_hash_long_urls() -> Result<()> {
+        let long = format!("https://example.com/search?q={}", "a".repeat(10_000));
+        let options = CacheOptions {
+            hash_keys: true,
+            ..Default::default()
+        };
+        let key = options.cache_key(&Request::new(Method::GET, Url::parse(&long)?));
+        assert!(key.len() < 200);
+        assert!(key.starts_with("GET:https://example.com/search?q=aaa"));
+        assert!(key_has_url_prefix(&key, "https://example.com/search"));
+        // Deterministic, and different for every URL
+        let again = options.cache_key(&Request::new(Method::GET, Url::parse(&long)?));
+        assert_eq!(key, again);
+        let other = format!("{}b", long);
+        let other = options.cache_key(&Request::new(Method::GET, Url::parse(&other)?));
+        assert_ne!(key, other);
+        Ok(())
+    }
+
+    #[cfg(not(feature = "hash-keys"))]
+    #[test]
+    fn hashing_keys_requires_the_feature() {
+        let built = Cache::builder()
+            .cache_manager(crate::managers::HashMapManager::new())
+            .hash_keys(true)
+            .build();
+        assert!(built.is_err());
+    }
+
+    #[tokio::test]
+    async fn single_flight_forgets_finished_keys() -> Result<()> {
+        let single_flight = SingleFlight::new();
+        let first = single_flight.acquire("a".into()).await;
+        let waiting = tokio::spawn({
+            let single_flight = single_flight.clone();
+            async move {
+                let _flight = single_flight.acquire("a".into()).await;
+            }
+        });
+        tokio::task::yield_now().await;
+        drop(first);
+        waiting.await?;
+        assert!(single_flight.flights().is_empty());
+
+        // Also when the request in flight panics
+        let panicking = tokio::spawn({
+            let single_flight = single_flight.clone();
+            async move {
+                let _flight = single_flight.acquire("b".into()).await;
+                panic!("upstream request panicked");
+            }
+        });
+        assert!(panicking.await.is_err());
+        assert!(single_flight.flights().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn can_add_validators() -> Result<()> {
+        let url = Url::from_str("https://example.com")?;
+        let stored = reqwest::Response::from(
+            http::Response::builder()
+                .header(ETAG, "\"abc\"")
+                .header(LAST_MODIFIED, "Wed, 21 Oct 2015 07:28:00 GMT")
+                .body("")?,
+        );
+        let mut req = Request::new(Method::GET, url.clone());
+        add_validators(&stored, &mut req);
+        assert_eq!(req.headers()[IF_NONE_MATCH], "\"abc\"");
+        assert_eq!(
+            req.headers()[IF_MODIFIED_SINCE],
+            "Wed, 21 Oct 2015 07:28:00 GMT"
+        );
+
+        // Conditions sent by the caller are kept
+        let mut req = Request::new(Method::GET, url);
+        req.headers_mut()
+            .insert(IF_NONE_MATCH, HeaderValue::from_static("\"xyz\""));
+        add_validators(&stored, &mut req);
+        assert_eq!(req.headers()[IF_NONE_MATCH], "\"xyz\"");
+        Ok(())
+    }
+
+    #[test]
+    fn can_normalize_query() -> Result<()> {
+        let url = Url::from_str("https://example.com/?b=2&utm_source=x&a=1&cachebust=9")?;
+        let ignored = vec!["utm_*".to_string(), "cachebust".to_string()];
+        assert_eq!(normalize_url(&url, false, &[]), url);
+        assert_eq!(
+            normalize_url(&url, true, &[]).as_str(),
+            "https://example.com/?a=1&b=2&cachebust=9&utm_source=x"
+        );
+        assert_eq!(
+            normalize_url(&url, false, &ignored).as_str(),
+            "https://example.com/?b=2&a=1"
+        );
+        assert_eq!(
+            normalize_url(&url, true, &ignored).as_str(),
+            "https://example.com/?a=1&b=2"
+        );
+        let url = Url::from_str("https://example.com/?utm_medium=y")?;
+        assert_eq!(
+            normalize_url(&url, false, &ignored).as_str(),
+            "https://example.com/"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn can_canonicalize_url() -> Result<()> {
+        for (url, canonical) in &[
+            ("https://example.com:443/docs/", "https://example.com/docs"),
+            ("https://example.com/docs?", "https://example.com/docs"),
+            (
+                "https://example.com/docs/?a=1",
+                "https://example.com/docs?a=1",
+            ),
+            ("https://example.com/", "https://example.com/"),
+            ("https://example.com:8443//", "https://example.com:8443/"),
+        ] {
+            assert_eq!(canonicalize_url(Url::from_str(url)?).as_str(), *canonical);
+        }
+        Ok(())
+    }
 }
@@ -1,178 +1,672 @@
-use std::collections::HashMap;
-use std::convert::{TryFrom, TryInto};
+#[cfg(feature = "compression")]
+use std::io::{Read, Write};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use crate::CacheManager;
+use super::store::{
+    decode_store, encode_store_as, from_store, from_store_with_body, store_parts, Serialization,
+    Store,
+};
+use crate::{key_has_url_prefix, CacheManager, CacheStats};
 
 use anyhow::{anyhow, Result};
-use http::version::Version;
-use http_cache_semantics::CachePolicy;
-use reqwest::{
-    header::{HeaderName, HeaderValue},
-    Request, Response, ResponseBuilderExt,
+use cacache::{Algorithm, Metadata, Reader, Value, WriteOpts};
+use futures::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    stream,
 };
-use serde::{Deserialize, Serialize};
-use url::Url;
+use http_cache_semantics::CachePolicy;
+use reqwest::{Body, Response};
+use ssri::Integrity;
+
+// Size of the chunks read back out of the cache when streaming a body.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Prepended to the body integrity stored in the index for zstd compressed bodies.
+const ZSTD_MARKER: &str = "zstd:";
+
+// Magic bytes every gzip stream starts with, telling gzipped records apart from
+// bincode and JSON ones.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
 
 /// Implements [`CacheManager`] with [`cacache`](https://github.com/zkat/cacache-rs) as the backend.
+///
+/// Bodies are written apart from their records and addressed by hash, so an
+/// identical body cached under several keys is only stored once.
+///
+/// With [`max_size`](CACacheManager::max_size) set, every `put` that takes the cache
+/// over that many bytes evicts the least recently used entries until it fits again.
+/// Reads and writes made through this manager (and its clones) count as uses, entries
+/// it hasn't seen since startup are ordered by when they were written. The size of
+/// the cache is read from its index on the first write and tracked from then on, so
+/// writes made by other processes in the meantime aren't counted.
 #[derive(Debug, Clone)]
 pub struct CACacheManager {
     /// Directory where the cache will be stored.
     pub path: String,
+    /// Compress bodies with zstd before writing them, requires the `compression` feature.
+    /// Entries are always read back correctly, whichever way they were written.
+    pub compress: bool,
+    /// Gzip records, the headers and policy stored apart from the body, before
+    /// writing them. Requires the `compression` feature, records are always read
+    /// back correctly, whichever way they were written.
+    pub compress_records: bool,
+    /// Maximum number of bytes taken up by records and bodies, unbounded when unset.
+    /// Bodies shared by several entries count once towards it.
+    pub max_size: Option<u64>,
+    /// How records are serialized, [`Serialization::Bincode`] by default. Bodies are
+    /// stored as-is either way.
+    pub serialization: Serialization,
+    // Last use of each key, in milliseconds since the epoch like cacache's index.
+    accessed: Arc<Mutex<HashMap<String, u128>>>,
+    // Live entries and the bytes they take up, read from the index on the first
+    // write with `max_size` set and kept up to date from then on.
+    usage: Arc<Mutex<Option<Usage>>>,
+}
+
+// The content each live entry points at, with sizes and reference counts by hash
+// so content shared between entries is counted, and removed, once.
+#[derive(Debug, Default)]
+struct Usage {
+    // Record hash, body hash and time written of each key.
+    entries: HashMap<String, (String, Option<String>, u128)>,
+    // Size of each record or body and how many entries point at it.
+    content: HashMap<String, (u64, usize)>,
+    // Sum of the sizes in `content`.
+    total: u64,
+}
+
+impl Usage {
+    fn from_entries(entries: &[Metadata]) -> Self {
+        let mut usage = Usage::default();
+        for entry in entries {
+            let record = (entry.integrity.to_string(), entry.size as u64);
+            usage.insert(
+                &entry.key,
+                record,
+                body_content(&entry.metadata),
+                entry.time,
+            );
+        }
+        usage
+    }
+
+    // Points `key` at `record` and `body`, replacing whatever it pointed at before.
+    fn insert(
+        &mut self,
+        key: &str,
+        record: (String, u64),
+        body: Option<(String, u64)>,
+        time: u128,
+    ) {
+        self.remove(key);
+        let body_sri = body.as_ref().map(|(sri, _)| sri.clone());
+        for (sri, size) in std::iter::once(record.clone()).chain(body) {
+            let (_, refs) = match self.content.entry(sri) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    self.total += size;
+                    entry.insert((size, 0))
+                }
+            };
+            *refs += 1;
+        }
+        self.entries
+            .insert(key.to_owned(), (record.0, body_sri, time));
+    }
+
+    // Forgets `key`, returning the hashes of the content no other entry points at.
+    fn remove(&mut self, key: &str) -> Vec<String> {
+        let (record, body, _) = match self.entries.remove(key) {
+            Some(entry) => entry,
+            None => return Vec::new(),
+        };
+        let mut freed = Vec::new();
+        for sri in std::iter::once(record).chain(body) {
+            if let Some((size, refs)) = self.content.get_mut(&sri) {
+                *refs -= 1;
+                if *refs == 0 {
+                    self.total -= *size;
+                    self.content.remove(&sri);
+                    freed.push(sri);
+                }
+            }
+        }
+        freed
+    }
 }
 
 impl Default for CACacheManager {
     fn default() -> Self {
         CACacheManager {
             path: "./reqwest-cacache".into(),
+            compress: false,
+            compress_records: false,
+            max_size: None,
+            serialization: Serialization::default(),
+            accessed: Arc::default(),
+            usage: Arc::default(),
         }
     }
 }
 
-// HTTP version enum in the http crate does not support serde, hence the modified copy.
-#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
-enum HttpVersion {
-    #[serde(rename = "HTTP/0.9")]
-    Http09,
-    #[serde(rename = "HTTP/1.0")]
-    Http10,
-    #[serde(rename = "HTTP/1.1")]
-    Http11,
-    #[serde(rename = "HTTP/2.0")]
-    H2,
-    #[serde(rename = "HTTP/3.0")]
-    H3,
-}
+#[allow(dead_code)]
+impl CACacheManager {
+    /// Creates a manager storing under `path`, with the other settings left at
+    /// their defaults. Paths that aren't valid UTF-8 are converted lossily.
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        CACacheManager {
+            path: path.as_ref().to_string_lossy().into_owned(),
+            ..Default::default()
+        }
+    }
 
-impl TryFrom<Version> for HttpVersion {
-    type Error = anyhow::Error;
-
-    fn try_from(value: Version) -> Result<Self> {
-        Ok(match value {
-            Version::HTTP_09 => HttpVersion::Http09,
-            Version::HTTP_10 => HttpVersion::Http10,
-            Version::HTTP_11 => HttpVersion::Http11,
-            Version::HTTP_2 => HttpVersion::H2,
-            Version::HTTP_3 => HttpVersion::H3,
-            _ => return Err(anyhow!("Unknown HTTP version")),
-        })
+    /// Creates a manager storing at most `max_size` bytes under `path`, see
+    /// [`CACacheManager`] for how entries are evicted.
+    pub fn with_max_size(path: impl AsRef<Path>, max_size: u64) -> Self {
+        CACacheManager {
+            max_size: Some(max_size),
+            ..CACacheManager::new(path)
+        }
+    }
+
+    /// Clears out the entire cache.
+    pub async fn clear(&self) -> Result<()> {
+        cacache::clear(&self.path).await?;
+        if let Ok(mut accessed) = self.accessed.lock() {
+            accessed.clear();
+        }
+        if let Ok(mut usage) = self.usage.lock() {
+            *usage = None;
+        }
+        Ok(())
+    }
+
+    fn encode_record(&self, store: &Store) -> Result<Vec<u8>> {
+        let record = encode_store_as(store, self.serialization)?;
+        if !self.compress_records {
+            return Ok(record);
+        }
+        #[cfg(feature = "compression")]
+        {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&record)?;
+            Ok(encoder.finish()?)
+        }
+        #[cfg(not(feature = "compression"))]
+        {
+            Err(anyhow!(
+                "Compressing records requires the `compression` feature"
+            ))
+        }
+    }
+
+    fn touch(&self, cache_key: &str) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis());
+        if let Ok(mut accessed) = self.accessed.lock() {
+            accessed.insert(cache_key.to_owned(), now);
+        }
+    }
+
+    // Records what `cache_key` now points at, then removes least recently used
+    // entries, other than `cache_key`, until the cache fits in `max_size`.
+    async fn evict(
+        &self,
+        cache_key: &str,
+        record: (String, u64),
+        body: Option<(String, u64)>,
+    ) -> Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis());
+        let max_size = match self.max_size {
+            Some(max_size) => max_size,
+            None => {
+                if let Ok(mut usage) = self.usage.lock() {
+                    if let Some(usage) = usage.as_mut() {
+                        usage.insert(cache_key, record, body, now);
+                    }
+                }
+                return Ok(());
+            }
+        };
+        if self.lock_usage()?.is_none() {
+            let path = self.path.clone();
+            let entries = tokio::task::spawn_blocking(move || live_entries(&path)).await??;
+            self.lock_usage()?
+                .get_or_insert_with(|| Usage::from_entries(&entries));
+        }
+        let (evicted, freed) = {
+            let mut usage = self.lock_usage()?;
+            let usage = usage.get_or_insert_with(Usage::default);
+            usage.insert(cache_key, record, body, now);
+            if usage.total <= max_size {
+                return Ok(());
+            }
+            let mut candidates: Vec<(u128, String)> = {
+                let accessed = self
+                    .accessed
+                    .lock()
+                    .map_err(|_| anyhow!("CACacheManager access times lock was poisoned"))?;
+                usage
+                    .entries
+                    .iter()
+                    .filter(|(key, _)| key.as_str() != cache_key)
+                    .map(|(key, (_, _, time))| {
+                        (accessed.get(key).copied().unwrap_or(*time), key.clone())
+                    })
+                    .collect()
+            };
+            candidates.sort();
+            let mut evicted = Vec::new();
+            let mut freed = Vec::new();
+            for (_, key) in candidates {
+                if usage.total <= max_size {
+                    break;
+                }
+                freed.extend(usage.remove(&key));
+                evicted.push(key);
+            }
+            (evicted, freed)
+        };
+        for key in evicted {
+            cacache::remove(&self.path, &key).await?;
+            if let Ok(mut accessed) = self.accessed.lock() {
+                accessed.remove(&key);
+            }
+        }
+        for sri in freed {
+            self.remove_content(&sri.parse()?).await?;
+        }
+        Ok(())
+    }
+
+    fn lock_usage(&self) -> Result<std::sync::MutexGuard<'_, Option<Usage>>> {
+        self.usage
+            .lock()
+            .map_err(|_| anyhow!("CACacheManager usage lock was poisoned"))
+    }
+
+    // Removes content by hash, content that is already gone counts as removed.
+    async fn remove_content(&self, sri: &Integrity) -> Result<()> {
+        match cacache::remove_hash(&self.path, sri).await {
+            Err(e) if cacache::exists(&self.path, sri).await => Err(e.into()),
+            _ => Ok(()),
+        }
     }
 }
 
-impl From<HttpVersion> for Version {
-    fn from(value: HttpVersion) -> Self {
-        match value {
-            HttpVersion::Http09 => Version::HTTP_09,
-            HttpVersion::Http10 => Version::HTTP_10,
-            HttpVersion::Http11 => Version::HTTP_11,
-            HttpVersion::H2 => Version::HTTP_2,
-            HttpVersion::H3 => Version::HTTP_3,
+// The current entry of every key in the cache. `cacache::list_sync` yields the
+// first line written for each key, which outlives both deletes and rewrites, so
+// every key it lists is looked up again.
+fn live_entries(path: &str) -> cacache::Result<Vec<Metadata>> {
+    let mut entries = Vec::new();
+    for entry in cacache::list_sync(path) {
+        if let Some(entry) = cacache::metadata_sync(path, &entry?.key)? {
+            entries.push(entry);
         }
     }
+    Ok(entries)
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Store {
-    response: StoredResponse,
-    policy: CachePolicy,
+// Index metadata pointing at a separately stored body, `{"body": sri, "size": bytes}`
+// where the sri may carry a marker for its encoding. Entries written before sizes
+// were tracked hold the sri on its own.
+fn body_ref(metadata: &Value) -> Option<(&str, u64)> {
+    match metadata {
+        Value::String(body) => Some((body.as_str(), 0)),
+        Value::Object(map) => Some((
+            map.get("body")?.as_str()?,
+            map.get("size").and_then(Value::as_u64).unwrap_or(0),
+        )),
+        _ => None,
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct StoredResponse {
-    body: Vec<u8>,
-    headers: HashMap<String, String>,
-    status: u16,
-    url: Url,
-    version: HttpVersion,
+// The hash and size of the body an index entry points at, without its marker.
+fn body_content(metadata: &Value) -> Option<(String, u64)> {
+    body_ref(metadata).map(|(body, size)| (strip_marker(body).0.to_owned(), size))
 }
 
-async fn to_store(res: Response, policy: CachePolicy) -> Result<Store> {
-    let mut headers = HashMap::new();
-    for header in res.headers() {
-        headers.insert(header.0.as_str().to_owned(), header.1.to_str()?.to_owned());
-    }
-    let status = res.status().as_u16();
-    let url = res.url().clone();
-    let version = res.version().try_into()?;
-    let body: Vec<u8> = res.bytes().await?.to_vec();
-    Ok(Store {
-        response: StoredResponse {
-            body,
-            headers,
-            status,
-            url,
-            version,
-        },
-        policy,
-    })
+// Splits the encoding marker off a body reference, returning whether it's compressed.
+fn strip_marker(body: &str) -> (&str, bool) {
+    match body.strip_prefix(ZSTD_MARKER) {
+        Some(sri) => (sri, true),
+        None => (body, false),
+    }
 }
 
-fn from_store(store: &Store) -> Result<Response> {
-    let mut res = http::Response::builder()
-        .status(store.response.status)
-        .url(store.response.url.clone())
-        .version(store.response.version.into())
-        .body(store.response.body.clone())?;
-    for header in &store.response.headers {
-        res.headers_mut().insert(
-            HeaderName::from_lowercase(header.0.clone().as_str().to_lowercase().as_bytes())?,
-            HeaderValue::from_str(header.1.clone().as_str())?,
-        );
+// The record as written by `encode_store_as`, gunzipped if need be. Returns `None`
+// when the record is gzipped and compression support is not built in.
+fn decompress_record(record: Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+    if !record.starts_with(&GZIP_MAGIC) {
+        return Ok(Some(record));
+    }
+    #[cfg(feature = "compression")]
+    {
+        let mut plain = Vec::new();
+        flate2::read::GzDecoder::new(&record[..]).read_to_end(&mut plain)?;
+        Ok(Some(plain))
+    }
+    #[cfg(not(feature = "compression"))]
+    {
+        Ok(None)
     }
-    Ok(Response::from(res))
 }
 
-fn req_key(req: &Request) -> String {
-    format!("{}:{}", req.method(), req.url())
+#[cfg(feature = "compression")]
+type Decoder = zstd::stream::write::Decoder<'static, Vec<u8>>;
+
+struct BodyReader {
+    reader: Reader,
+    #[cfg(feature = "compression")]
+    decoder: Option<Decoder>,
 }
 
-#[allow(dead_code)]
-impl CACacheManager {
-    /// Clears out the entire cache.
-    pub async fn clear(&self) -> Result<()> {
-        cacache::clear(&self.path).await?;
-        Ok(())
+impl BodyReader {
+    // Returns `None` when the body is compressed and compression support is not built in.
+    fn new(reader: Reader, compressed: bool) -> io::Result<Option<Self>> {
+        #[cfg(feature = "compression")]
+        {
+            let decoder = if compressed {
+                Some(Decoder::new(Vec::new())?)
+            } else {
+                None
+            };
+            Ok(Some(BodyReader { reader, decoder }))
+        }
+        #[cfg(not(feature = "compression"))]
+        {
+            if compressed {
+                return Ok(None);
+            }
+            Ok(Some(BodyReader { reader }))
+        }
+    }
+
+    // Reads the next non-empty chunk of the (decompressed) body.
+    async fn next_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut buf = vec![0; CHUNK_SIZE];
+        loop {
+            let read = self.reader.read(&mut buf).await?;
+            if read == 0 {
+                return Ok(None);
+            }
+            buf.truncate(read);
+            #[cfg(feature = "compression")]
+            {
+                if let Some(decoder) = &mut self.decoder {
+                    decoder.write_all(&buf)?;
+                    decoder.flush()?;
+                    let out = std::mem::take(decoder.get_mut());
+                    if out.is_empty() {
+                        buf.resize(CHUNK_SIZE, 0);
+                        continue;
+                    }
+                    return Ok(Some(out));
+                }
+            }
+            return Ok(Some(buf));
+        }
     }
 }
 
+/// Streams a cached body out of `body`, verifying its integrity once fully read.
+fn body_stream(body: BodyReader) -> Body {
+    Body::wrap_stream(stream::try_unfold(body, |mut body| async move {
+        match body.next_chunk().await? {
+            Some(chunk) => Ok(Some((chunk, body))),
+            None => {
+                body.reader
+                    .check()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                Ok::<_, io::Error>(None)
+            }
+        }
+    }))
+}
+
 #[async_trait::async_trait]
 impl CacheManager for CACacheManager {
-    async fn get(&self, req: &Request) -> Result<Option<(Response, CachePolicy)>> {
-        let store: Store = match cacache::read(&self.path, &req_key(req)).await {
-            Ok(d) => bincode::deserialize(&d)?,
-            Err(_e) => {
+    async fn get(&self, cache_key: &str) -> Result<Option<(Response, CachePolicy)>> {
+        let entry = match cacache::metadata(&self.path, cache_key).await {
+            Ok(Some(entry)) => entry,
+            _ => return Ok(None),
+        };
+        let record = match cacache::read_hash(&self.path, &entry.integrity).await {
+            Ok(record) => record,
+            Err(_e) => return Ok(None),
+        };
+        let record = match decompress_record(record) {
+            Ok(Some(record)) => record,
+            _ => return Ok(None),
+        };
+        let store: Store = match decode_store(cache_key, &record) {
+            Some(store) => store,
+            None => {
+                self.delete(cache_key).await?;
                 return Ok(None);
             }
         };
-        Ok(Some((from_store(&store)?, store.policy)))
+        // Bodies are written on their own and referenced from the index entry,
+        // records without that reference still carry their body inline.
+        let cached = match body_ref(&entry.metadata) {
+            Some((body, _)) => {
+                let (sri, compressed) = strip_marker(body);
+                let sri: Integrity = sri.parse()?;
+                let reader = match Reader::open_hash(&self.path, sri).await {
+                    Ok(reader) => reader,
+                    Err(_e) => return Ok(None),
+                };
+                match BodyReader::new(reader, compressed)? {
+                    Some(body) => (
+                        from_store_with_body(&store, body_stream(body))?,
+                        store.policy,
+                    ),
+                    None => return Ok(None),
+                }
+            }
+            None => from_store(store)?,
+        };
+        self.touch(cache_key);
+        Ok(Some(cached))
+    }
+
+    /// Reads the record alone, leaving the separately stored body untouched.
+    async fn get_policy(&self, cache_key: &str) -> Result<Option<CachePolicy>> {
+        let entry = match cacache::metadata(&self.path, cache_key).await {
+            Ok(Some(entry)) => entry,
+            _ => return Ok(None),
+        };
+        let record = match cacache::read_hash(&self.path, &entry.integrity).await {
+            Ok(record) => record,
+            Err(_e) => return Ok(None),
+        };
+        let record = match decompress_record(record) {
+            Ok(Some(record)) => record,
+            _ => return Ok(None),
+        };
+        match decode_store(cache_key, &record) {
+            Some(store) => {
+                self.touch(cache_key);
+                Ok(Some(store.policy))
+            }
+            None => {
+                self.delete(cache_key).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    async fn put(
+        &self,
+        cache_key: &str,
+        mut res: Response,
+        policy: CachePolicy,
+    ) -> Result<Response> {
+        let compress = self.compress;
+        if compress && cfg!(not(feature = "compression")) {
+            return Err(anyhow!(
+                "Compressing bodies requires the `compression` feature"
+            ));
+        }
+        if self.compress_records && cfg!(not(feature = "compression")) {
+            return Err(anyhow!(
+                "Compressing records requires the `compression` feature"
+            ));
+        }
+        let store = store_parts(&res, policy)?;
+        let mut body = WriteOpts::new()
+            .algorithm(Algorithm::Sha256)
+            .open_hash(&self.path)
+            .await?;
+        let mut body_size = 0;
+        #[cfg(feature = "compression")]
+        {
+            if compress {
+                let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), 0)?;
+                while let Some(chunk) = res.chunk().await? {
+                    encoder.write_all(&chunk)?;
+                    body.write_all(encoder.get_ref()).await?;
+                    body_size += encoder.get_ref().len();
+                    encoder.get_mut().clear();
+                }
+                let rest = encoder.finish()?;
+                body.write_all(&rest).await?;
+                body_size += rest.len();
+            }
+        }
+        if !compress {
+            while let Some(chunk) = res.chunk().await? {
+                body.write_all(&chunk).await?;
+                body_size += chunk.len();
+            }
+        }
+        let body_sri = body.commit().await?;
+        let marker = if compress { ZSTD_MARKER } else { "" };
+        let record = self.encode_record(&store)?;
+        let metadata: Value = vec![
+            ("body", Value::from(format!("{}{}", marker, body_sri))),
+            ("size", Value::from(body_size as u64)),
+        ]
+        .into_iter()
+        .collect();
+        let mut writer = WriteOpts::new()
+            .algorithm(Algorithm::Sha256)
+            .size(record.len())
+            .metadata(metadata)
+            .open(&self.path, cache_key)
+            .await?;
+        writer.write_all(&record).await?;
+        let record_sri = writer.commit().await?;
+        self.touch(cache_key);
+        self.evict(
+            cache_key,
+            (record_sri.to_string(), record.len() as u64),
+            Some((body_sri.to_string(), body_size as u64)),
+        )
+        .await?;
+        let reader = Reader::open_hash(&self.path, body_sri).await?;
+        let body = BodyReader::new(reader, compress)?
+            .expect("compressed bodies are only written with compression support");
+        from_store_with_body(&store, body_stream(body))
+    }
+
+    /// Rewrites the record alone when the body is stored on its own, which it is
+    /// for every entry but those written by older versions.
+    async fn update_metadata(
+        &self,
+        cache_key: &str,
+        res: Response,
+        policy: CachePolicy,
+    ) -> Result<Response> {
+        let metadata = match cacache::metadata(&self.path, cache_key).await? {
+            Some(entry) if body_ref(&entry.metadata).is_some() => entry.metadata,
+            _ => return self.put(cache_key, res, policy).await,
+        };
+        let body = body_content(&metadata);
+        let store = store_parts(&res, policy)?;
+        let record = self.encode_record(&store)?;
+        let mut writer = WriteOpts::new()
+            .algorithm(Algorithm::Sha256)
+            .size(record.len())
+            .metadata(metadata)
+            .open(&self.path, cache_key)
+            .await?;
+        writer.write_all(&record).await?;
+        let record_sri = writer.commit().await?;
+        self.touch(cache_key);
+        self.evict(
+            cache_key,
+            (record_sri.to_string(), record.len() as u64),
+            body,
+        )
+        .await?;
+        Ok(res)
     }
 
-    // TODO - This needs some reviewing.
-    async fn put(&self, req: &Request, res: Response, policy: CachePolicy) -> Result<Response> {
-        let status = res.status();
-        let url = res.url().clone();
-        let version = res.version();
-        let headers = res.headers().clone();
-        let data = to_store(res, policy).await?;
-        let bytes = bincode::serialize(&data)?;
-        cacache::write(&self.path, &req_key(req), bytes).await?;
-        let mut ret_res = http::Response::builder()
-            .status(status)
-            .url(url)
-            .version(version)
-            .body(data.response.body)?;
-        for header in headers {
-            ret_res
-                .headers_mut()
-                .insert(header.0.unwrap(), header.1.clone());
+    async fn delete(&self, cache_key: &str) -> Result<()> {
+        cacache::remove(&self.path, cache_key).await?;
+        if let Ok(mut accessed) = self.accessed.lock() {
+            accessed.remove(cache_key);
         }
-        *ret_res.version_mut() = version;
-        Ok(Response::from(ret_res))
+        if let Ok(mut usage) = self.usage.lock() {
+            if let Some(usage) = usage.as_mut() {
+                usage.remove(cache_key);
+            }
+        }
+        Ok(())
+    }
+
+    async fn keys(&self) -> Result<Vec<String>> {
+        let path = self.path.clone();
+        let entries = tokio::task::spawn_blocking(move || live_entries(&path)).await??;
+        Ok(entries.into_iter().map(|entry| entry.key).collect())
     }
 
-    async fn delete(&self, req: &Request) -> Result<()> {
-        cacache::remove(&self.path, &req_key(req)).await?;
+    async fn invalidate_prefix(&self, prefix: &str) -> Result<()> {
+        for key in self.keys().await? {
+            if key_has_url_prefix(&key, prefix) {
+                self.delete(&key).await?;
+            }
+        }
         Ok(())
     }
+
+    /// Computed from the index alone, a body shared by several entries is counted
+    /// once.
+    async fn stats(&self) -> Result<CacheStats> {
+        let path = self.path.clone();
+        let entries = tokio::task::spawn_blocking(move || live_entries(&path)).await??;
+        Ok(CacheStats {
+            entries: entries.len() as u64,
+            bytes: Usage::from_entries(&entries).total,
+        })
+    }
+
+    /// Reads only the records, leaving the bodies unread.
+    async fn clear_expired(&self) -> Result<usize> {
+        let now = SystemTime::now();
+        let mut removed = 0;
+        for key in self.keys().await? {
+            // Records that can't be read have already been dropped by `get_policy`.
+            if let Some(policy) = self.get_policy(&key).await? {
+                if policy.is_stale(now) {
+                    self.delete(&key).await?;
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
 }
 
 #[cfg(test)]
@@ -183,24 +677,396 @@ mod tests {
     use reqwest::Request;
     use std::str::FromStr;
 
+    // Bytes taken up by an entry, its record plus its body when stored separately.
+    fn entry_size(entry: &Metadata) -> u64 {
+        entry.size as u64 + body_ref(&entry.metadata).map_or(0, |(_, size)| size)
+    }
+
     #[tokio::test]
     async fn can_cache_response() -> Result<()> {
         let url = reqwest::Url::from_str("https://example.com")?;
         let res = Response::new("test");
         let res = reqwest::Response::from(res);
+        let key = format!("GET:{}", &url);
         let req = Request::new(Method::GET, url);
         let policy = CachePolicy::new(&req, &res);
         let manager = CACacheManager::default();
-        manager.put(&req, res, policy).await?;
-        let data = manager.get(&req).await?;
+        let res = manager.put(&key, res, policy).await?;
+        assert_eq!(res.text().await?, "test");
+        assert!(manager.keys().await?.contains(&key));
+        let data = manager.get(&key).await?;
         let body = match data {
             Some(d) => d.0.text().await?,
             None => String::new(),
         };
         assert_eq!(&body, "test");
-        manager.delete(&req).await?;
-        let data = manager.get(&req).await?;
+        manager.delete(&key).await?;
+        let data = manager.get(&key).await?;
         assert!(data.is_none());
+        assert!(!manager.keys().await?.contains(&key));
+        manager.clear().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reports_stats() -> Result<()> {
+        let manager = CACacheManager::new(Path::new(".").join("reqwest-cacache-stats"));
+        for path in &["a", "b"] {
+            let url = reqwest::Url::from_str(&format!("https://example.com/{}", path))?;
+            let key = format!("GET:{}", &url);
+            let req = Request::new(Method::GET, url);
+            let res = reqwest::Response::from(Response::new(path.repeat(100)));
+            let policy = CachePolicy::new(&req, &res);
+            manager.put(&key, res, policy).await?;
+        }
+        let stats = manager.stats().await?;
+        assert_eq!(stats.entries, 2);
+        assert!(stats.bytes > 200);
+        // Deleted and rewritten keys are counted as they are now
+        let url = reqwest::Url::from_str("https://example.com/a")?;
+        let key = format!("GET:{}", &url);
+        let req = Request::new(Method::GET, url);
+        let res = reqwest::Response::from(Response::new("a"));
+        let policy = CachePolicy::new(&req, &res);
+        manager.put(&key, res, policy).await?;
+        manager.delete("GET:https://example.com/b").await?;
+        let shrunk = manager.stats().await?;
+        assert_eq!(shrunk.entries, 1);
+        assert!(shrunk.bytes < stats.bytes / 2);
+        manager.clear().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reads_policy_alone() -> Result<()> {
+        let url = reqwest::Url::from_str("https://example.com/policy")?;
+        let res = http::Response::builder()
+            .header("cache-control", "max-age=60")
+            .body("test")?;
+        let res = reqwest::Response::from(res);
+        let key = format!("GET:{}", &url);
+        let req = Request::new(Method::GET, url);
+        let policy = CachePolicy::new(&req, &res);
+        let manager = CACacheManager::new("./reqwest-cacache-policy");
+        assert!(manager.get_policy(&key).await?.is_none());
+        manager.put(&key, res, policy).await?;
+        let policy = manager
+            .get_policy(&key)
+            .await?
+            .expect("policy should be cached");
+        assert!(policy.is_storable());
+        assert!(!policy.is_stale(std::time::SystemTime::now()));
+        manager.clear().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn updates_headers_without_the_body() -> Result<()> {
+        let url = reqwest::Url::from_str("https://example.com/metadata")?;
+        let res = reqwest::Response::from(Response::new("test"));
+        let key = format!("GET:{}", &url);
+        let req = Request::new(Method::GET, url);
+        let policy = CachePolicy::new(&req, &res);
+        let manager = CACacheManager::new("./reqwest-cacache-metadata");
+        manager.put(&key, res, policy.clone()).await?;
+        let body = cacache::metadata(&manager.path, &key)
+            .await?
+            .and_then(|entry| body_ref(&entry.metadata).map(|(body, _)| body.to_owned()));
+        let (mut res, _) = manager.get(&key).await?.expect("response should be cached");
+        res.headers_mut()
+            .insert("etag", http::HeaderValue::from_static("\"v2\""));
+        let res = manager.update_metadata(&key, res, policy).await?;
+        assert_eq!(res.text().await?, "test");
+        let (res, _) = manager.get(&key).await?.expect("response should be cached");
+        assert_eq!(res.headers()["etag"], "\"v2\"");
+        assert_eq!(res.text().await?, "test");
+        let entry = cacache::metadata(&manager.path, &key).await?;
+        assert_eq!(
+            entry.and_then(|entry| body_ref(&entry.metadata).map(|(body, _)| body.to_owned())),
+            body
+        );
+        manager.clear().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn clears_expired_records() -> Result<()> {
+        let manager = CACacheManager::new("./reqwest-cacache-expired");
+        let mut keys = Vec::new();
+        for (path, cache_control) in &[("stale", "max-age=0"), ("fresh", "max-age=3600")] {
+            let url = reqwest::Url::from_str(&format!("https://example.com/{}", path))?;
+            let key = format!("GET:{}", &url);
+            let req = Request::new(Method::GET, url);
+            let res = http::Response::builder()
+                .header("cache-control", *cache_control)
+                .body("test")?;
+            let res = reqwest::Response::from(res);
+            let policy = CachePolicy::new(&req, &res);
+            manager.put(&key, res, policy).await?;
+            keys.push(key);
+        }
+        assert_eq!(manager.clear_expired().await?, 1);
+        assert!(manager.get(&keys[0]).await?.is_none());
+        assert!(manager.get(&keys[1]).await?.is_some());
+        manager.clear().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shares_identical_bodies() -> Result<()> {
+        let manager = CACacheManager::new("./reqwest-cacache-dedup");
+        let mut keys = Vec::new();
+        for path in &["a", "b"] {
+            let url = reqwest::Url::from_str(&format!("https://example.com/{}", path))?;
+            let key = format!("GET:{}", &url);
+            let req = Request::new(Method::GET, url);
+            let res = reqwest::Response::from(Response::new("{}"));
+            let policy = CachePolicy::new(&req, &res);
+            manager.put(&key, res, policy).await?;
+            keys.push(key);
+        }
+        let mut bodies = Vec::new();
+        for key in &keys {
+            let entry = cacache::metadata(&manager.path, key).await?;
+            bodies.push(
+                entry.and_then(|entry| body_ref(&entry.metadata).map(|(body, _)| body.to_owned())),
+            );
+        }
+        assert!(bodies[0].is_some());
+        assert_eq!(bodies[0], bodies[1]);
+        // The shared body is counted once
+        let entry = cacache::metadata(&manager.path, &keys[0])
+            .await?
+            .expect("entry should exist");
+        assert_eq!(
+            manager.stats().await?.bytes,
+            entry_size(&entry) + entry.size as u64
+        );
+        // Removing one key leaves the shared body in place for the other
+        manager.delete(&keys[0]).await?;
+        let (res, _) = manager
+            .get(&keys[1])
+            .await?
+            .expect("response should be cached");
+        assert_eq!(res.text().await?, "{}");
+        manager.clear().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn drops_undecodable_records() -> Result<()> {
+        let manager = CACacheManager {
+            path: "./reqwest-cacache-corrupt".into(),
+            ..Default::default()
+        };
+        let key = "GET:https://example.com/corrupt";
+        cacache::write(&manager.path, key, b"not a record").await?;
+        assert!(manager.get(key).await?.is_none());
+        assert!(cacache::metadata(&manager.path, key).await?.is_none());
+        manager.clear().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn streams_large_bodies() -> Result<()> {
+        let url = reqwest::Url::from_str("https://example.com/large")?;
+        let body: Vec<u8> = (0..CHUNK_SIZE * 3 + 17).map(|i| i as u8).collect();
+        let res = reqwest::Response::from(Response::new(body.clone()));
+        let key = format!("GET:{}", &url);
+        let req = Request::new(Method::GET, url);
+        let policy = CachePolicy::new(&req, &res);
+        let manager = CACacheManager {
+            path: "./reqwest-cacache-streaming".into(),
+            ..Default::default()
+        };
+        let res = manager.put(&key, res, policy).await?;
+        assert_eq!(res.bytes().await?.to_vec(), body);
+        let (res, _) = manager.get(&key).await?.expect("response should be cached");
+        assert_eq!(res.bytes().await?.to_vec(), body);
+        manager.clear().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reads_bodies_back_in_chunks() -> Result<()> {
+        let url = reqwest::Url::from_str("https://example.com/chunked")?;
+        let body: Vec<u8> = (0..CHUNK_SIZE * 2).map(|i| i as u8).collect();
+        let res = reqwest::Response::from(Response::new(body.clone()));
+        let key = format!("GET:{}", &url);
+        let req = Request::new(Method::GET, url);
+        let policy = CachePolicy::new(&req, &res);
+        let manager = CACacheManager::new("./reqwest-cacache-chunks");
+        manager.put(&key, res, policy).await?;
+        let (mut res, _) = manager.get(&key).await?.expect("response should be cached");
+        // The body is streamed from disk rather than read in full by `get`
+        let first = res.chunk().await?.expect("body should not be empty");
+        assert!(first.len() <= CHUNK_SIZE);
+        assert_eq!(&first[..], &body[..first.len()]);
+        manager.clear().await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn reads_entries_written_with_either_encoding() -> Result<()> {
+        let url = reqwest::Url::from_str("https://example.com/compressed")?;
+        let body = "compress me ".repeat(10_000);
+        let key = format!("GET:{}", &url);
+        let req = Request::new(Method::GET, url);
+        let plain = CACacheManager {
+            path: "./reqwest-cacache-compression".into(),
+            compress: false,
+            ..Default::default()
+        };
+        let compressed = CACacheManager {
+            compress: true,
+            ..plain.clone()
+        };
+        let res = reqwest::Response::from(Response::new(body.clone()));
+        let policy = CachePolicy::new(&req, &res);
+        plain.put(&key, res, policy).await?;
+        let (res, _) = compressed
+            .get(&key)
+            .await?
+            .expect("response should be cached");
+        assert_eq!(res.text().await?, body);
+        let res = reqwest::Response::from(Response::new(body.clone()));
+        let policy = CachePolicy::new(&req, &res);
+        let res = compressed.put(&key, res, policy).await?;
+        assert_eq!(res.text().await?, body);
+        let (res, _) = plain.get(&key).await?.expect("response should be cached");
+        assert_eq!(res.text().await?, body);
+        plain.clear().await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn reads_records_written_with_either_encoding() -> Result<()> {
+        let url = reqwest::Url::from_str("https://example.com/gzipped")?;
+        let key = format!("GET:{}", &url);
+        let req = Request::new(Method::GET, url);
+        let plain = CACacheManager::new("./reqwest-cacache-gzip");
+        let gzipped = CACacheManager {
+            compress_records: true,
+            ..plain.clone()
+        };
+        let res = reqwest::Response::from(Response::new("test"));
+        let policy = CachePolicy::new(&req, &res);
+        gzipped.put(&key, res, policy).await?;
+        let entry = cacache::metadata(&plain.path, &key)
+            .await?
+            .expect("entry should exist");
+        let record = cacache::read_hash(&plain.path, &entry.integrity).await?;
+        assert!(record.starts_with(&GZIP_MAGIC));
+        let (res, _) = plain.get(&key).await?.expect("response should be cached");
+        assert_eq!(res.text().await?, "test");
+        let res = reqwest::Response::from(Response::new("test"));
+        let policy = CachePolicy::new(&req, &res);
+        plain.put(&key, res, policy).await?;
+        let (res, _) = gzipped.get(&key).await?.expect("response should be cached");
+        assert_eq!(res.text().await?, "test");
+        plain.clear().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_used() -> Result<()> {
+        let mut manager = CACacheManager::with_max_size("./reqwest-cacache-lru", u64::MAX);
+        let mut keys = Vec::new();
+        for path in &["a", "b", "c"] {
+            let url = reqwest::Url::from_str(&format!("https://example.com/{}", path))?;
+            let key = format!("GET:{}", &url);
+            let req = Request::new(Method::GET, url);
+            let res = reqwest::Response::from(Response::new(path.repeat(100)));
+            let policy = CachePolicy::new(&req, &res);
+            if keys.len() == 2 {
+                // Leave room for two entries and use the oldest one
+                let entry = cacache::metadata(&manager.path, &keys[0])
+                    .await?
+                    .expect("entry should exist");
+                manager.max_size = Some(entry_size(&entry) * 2);
+                manager.get(&keys[0]).await?;
+                tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            }
+            manager.put(&key, res, policy).await?;
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            keys.push(key);
+        }
+        assert!(manager.get(&keys[0]).await?.is_some());
+        assert!(manager.get(&keys[1]).await?.is_none());
+        assert!(manager.get(&keys[2]).await?.is_some());
+        manager.clear().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keeps_evicting_after_rewrites() -> Result<()> {
+        let mut manager = CACacheManager::with_max_size("./reqwest-cacache-rewrites", u64::MAX);
+        let mut keys = Vec::new();
+        for (path, body) in &[("a", "a"), ("b", "b"), ("a", "A"), ("c", "c"), ("d", "d")] {
+            let url = reqwest::Url::from_str(&format!("https://example.com/{}", path))?;
+            let key = format!("GET:{}", &url);
+            let req = Request::new(Method::GET, url);
+            let res = reqwest::Response::from(Response::new(body.repeat(100)));
+            let policy = CachePolicy::new(&req, &res);
+            if *path == "c" {
+                // Leave room for two entries, putting `c` evicts `b`
+                let entry = cacache::metadata(&manager.path, &keys[0])
+                    .await?
+                    .expect("entry should exist");
+                manager.max_size = Some(entry_size(&entry) * 2);
+            }
+            if *path == "d" {
+                // Reading the rewritten `a` makes `c` the one evicted by `d`
+                assert!(manager.get(&keys[1]).await?.is_none());
+                let (res, _) = manager
+                    .get(&keys[0])
+                    .await?
+                    .expect("response should be cached");
+                assert_eq!(res.text().await?, "A".repeat(100));
+                tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            }
+            manager.put(&key, res, policy).await?;
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        assert!(manager.get(&keys[1]).await?.is_none());
+        assert!(manager.get(&keys[2]).await?.is_none());
+        let (res, _) = manager
+            .get(&keys[0])
+            .await?
+            .expect("response should be cached");
+        assert_eq!(res.text().await?, "A".repeat(100));
+        assert!(manager.get(&keys[3]).await?.is_some());
+        manager.clear().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invalidates_by_prefix() -> Result<()> {
+        let manager = CACacheManager {
+            path: "./reqwest-cacache-prefix".into(),
+            ..Default::default()
+        };
+        let mut keys = Vec::new();
+        for path in &["users/1", "users/2", "teams/1"] {
+            let url = reqwest::Url::from_str(&format!("https://example.com/{}", path))?;
+            let res = reqwest::Response::from(Response::new("test"));
+            let key = format!("GET:{}", &url);
+            let req = Request::new(Method::GET, url);
+            let policy = CachePolicy::new(&req, &res);
+            manager.put(&key, res, policy).await?;
+            keys.push(key);
+        }
+        manager
+            .invalidate_prefix("https://example.com/users/")
+            .await?;
+        assert!(manager.get(&keys[0]).await?.is_none());
+        assert!(manager.get(&keys[1]).await?.is_none());
+        assert!(manager.get(&keys[2]).await?.is_some());
         manager.clear().await?;
         Ok(())
     }
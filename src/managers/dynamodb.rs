@@ -0,0 +1,164 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::store::{decode_store, encode_store, from_store, to_store};
+use crate::CacheManager;
+
+use anyhow::Result;
+use aws_sdk_dynamodb::{model::AttributeValue, types::Blob, Client};
+use http_cache_semantics::CachePolicy;
+use reqwest::Response;
+
+// Attribute names, chosen to steer clear of DynamoDB's reserved words.
+const KEY_ATTRIBUTE: &str = "cache_key";
+const RECORD_ATTRIBUTE: &str = "cache_record";
+const EXPIRES_ATTRIBUTE: &str = "expires_at";
+
+/// Implements [`CacheManager`] with [`DynamoDB`](https://github.com/awslabs/aws-sdk-rust) as the backend.
+///
+/// Records live in a table whose partition key is the string attribute `cache_key`.
+/// Each item also carries the time the response goes stale, in seconds since the epoch,
+/// as the number attribute `expires_at`. Enable DynamoDB's TTL on that attribute to have
+/// stale records swept, at the cost of no longer revalidating them.
+#[derive(Debug, Clone)]
+pub struct DynamoDbManager {
+    client: Client,
+    /// Table the records are stored in.
+    pub table_name: String,
+}
+
+impl DynamoDbManager {
+    /// Creates a new manager storing records in `table_name` through `client`.
+    pub fn new(client: Client, table_name: impl Into<String>) -> Self {
+        DynamoDbManager {
+            client,
+            table_name: table_name.into(),
+        }
+    }
+
+    /// Clears out the entire cache, one item at a time.
+    pub async fn clear(&self) -> Result<()> {
+        for key in self.keys().await? {
+            self.delete(&key).await?;
+        }
+        Ok(())
+    }
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+#[async_trait::async_trait]
+impl CacheManager for DynamoDbManager {
+    async fn get(&self, cache_key: &str) -> Result<Option<(Response, CachePolicy)>> {
+        let output = self
+            .client
+            .get_item()
+            .table_name(&self.table_name)
+            .key(KEY_ATTRIBUTE, AttributeValue::S(cache_key.to_owned()))
+            .send()
+            .await?;
+        let record = match output
+            .item()
+            .and_then(|item| item.get(RECORD_ATTRIBUTE))
+            .and_then(|record| record.as_b().ok())
+        {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+        let store = match decode_store(cache_key, record.as_ref()) {
+            Some(store) => store,
+            None => {
+                self.delete(cache_key).await?;
+                return Ok(None);
+            }
+        };
+        Ok(Some(from_store(store)?))
+    }
+
+    async fn put(&self, cache_key: &str, res: Response, policy: CachePolicy) -> Result<Response> {
+        let now = SystemTime::now();
+        let expires_at = unix_time(now + policy.time_to_live(now));
+        let data = to_store(res, policy).await?;
+        let record = encode_store(&data)?;
+        self.client
+            .put_item()
+            .table_name(&self.table_name)
+            .item(KEY_ATTRIBUTE, AttributeValue::S(cache_key.to_owned()))
+            .item(RECORD_ATTRIBUTE, AttributeValue::B(Blob::new(record)))
+            .item(EXPIRES_ATTRIBUTE, AttributeValue::N(expires_at.to_string()))
+            .send()
+            .await?;
+        Ok(from_store(data)?.0)
+    }
+
+    async fn delete(&self, cache_key: &str) -> Result<()> {
+        self.client
+            .delete_item()
+            .table_name(&self.table_name)
+            .key(KEY_ATTRIBUTE, AttributeValue::S(cache_key.to_owned()))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    async fn keys(&self) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut start_key = None;
+        loop {
+            let output = self
+                .client
+                .scan()
+                .table_name(&self.table_name)
+                .projection_expression("#k")
+                .expression_attribute_names("#k", KEY_ATTRIBUTE)
+                .set_exclusive_start_key(start_key)
+                .send()
+                .await?;
+            for item in output.items().unwrap_or_default() {
+                if let Some(AttributeValue::S(key)) = item.get(KEY_ATTRIBUTE) {
+                    keys.push(key.clone());
+                }
+            }
+            match output.last_evaluated_key() {
+                Some(last_key) => start_key = Some(last_key.clone()),
+                None => return Ok(keys),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use http::{Method, Response};
+    use reqwest::Request;
+    use std::str::FromStr;
+
+    #[tokio::test]
+    #[ignore = "requires AWS credentials and a `reqwest-cache-test` table keyed by `cache_key`"]
+    async fn can_cache_response() -> Result<()> {
+        let url = reqwest::Url::from_str("https://example.com")?;
+        let res = Response::new("test");
+        let res = reqwest::Response::from(res);
+        let key = format!("GET:{}", &url);
+        let req = Request::new(Method::GET, url);
+        let policy = CachePolicy::new(&req, &res);
+        let config = aws_config::load_from_env().await;
+        let manager = DynamoDbManager::new(Client::new(&config), "reqwest-cache-test");
+        manager.put(&key, res, policy).await?;
+        let data = manager.get(&key).await?;
+        let body = match data {
+            Some(d) => d.0.text().await?,
+            None => String::new(),
+        };
+        assert_eq!(&body, "test");
+        manager.delete(&key).await?;
+        let data = manager.get(&key).await?;
+        assert!(data.is_none());
+        manager.clear().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    io,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::store::{decode_store, encode_store_as, from_store, to_store, Serialization};
+use crate::CacheManager;
+
+use anyhow::Result;
+use http_cache_semantics::CachePolicy;
+use reqwest::Response;
+use serde::{Deserialize, Serialize};
+use ssri::Integrity;
+
+/// Implements [`CacheManager`] with a plain directory as the backend.
+///
+/// Every entry is written to `<root>/<sha256 of the key>.bin` holding the serialized record,
+/// next to a `<sha256 of the key>.json` sidecar naming the key, URL and expiry so the cache
+/// can be inspected and cleaned up by hand.
+#[derive(Debug, Clone)]
+pub struct FsManager {
+    /// Directory where the cache will be stored.
+    pub root: PathBuf,
+    /// How records are serialized, [`Serialization::Json`] makes them readable too.
+    pub serialization: Serialization,
+}
+
+impl Default for FsManager {
+    fn default() -> Self {
+        FsManager::new("./reqwest-fs-cache")
+    }
+}
+
+// Sidecar written next to each record, only read back to list keys.
+#[derive(Debug, Deserialize, Serialize)]
+struct Sidecar {
+    key: String,
+    url: String,
+    // Seconds since the epoch at which the response was stored and goes stale.
+    stored_at: u64,
+    expires_at: u64,
+}
+
+impl FsManager {
+    /// Creates a new manager storing entries under `root`.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        FsManager {
+            root: root.into(),
+            serialization: Serialization::default(),
+        }
+    }
+
+    /// Clears out the entire cache.
+    pub async fn clear(&self) -> Result<()> {
+        ignore_missing(tokio::fs::remove_dir_all(&self.root).await)?;
+        Ok(())
+    }
+
+    fn paths(&self, cache_key: &str) -> (PathBuf, PathBuf) {
+        let (_, hex) = Integrity::from(cache_key).to_hex();
+        (
+            self.root.join(format!("{}.bin", hex)),
+            self.root.join(format!("{}.json", hex)),
+        )
+    }
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+fn ignore_missing<T: Default>(result: io::Result<T>) -> io::Result<T> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+        result => result,
+    }
+}
+
+#[async_trait::async_trait]
+impl CacheManager for FsManager {
+    async fn get(&self, cache_key: &str) -> Result<Option<(Response, CachePolicy)>> {
+        let (record, _) = self.paths(cache_key);
+        let bytes = match tokio::fs::read(&record).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let store = match decode_store(cache_key, &bytes) {
+            Some(store) => store,
+            None => {
+                self.delete(cache_key).await?;
+                return Ok(None);
+            }
+        };
+        Ok(Some(from_store(store)?))
+    }
+
+    async fn put(&self, cache_key: &str, res: Response, policy: CachePolicy) -> Result<Response> {
+        let now = SystemTime::now();
+        let sidecar = Sidecar {
+            key: cache_key.to_owned(),
+            url: res.url().to_string(),
+            stored_at: unix_time(now),
+            expires_at: unix_time(now + policy.time_to_live(now)),
+        };
+        let data = to_store(res, policy).await?;
+        let (record, meta) = self.paths(cache_key);
+        tokio::fs::create_dir_all(&self.root).await?;
+        tokio::fs::write(&record, encode_store_as(&data, self.serialization)?).await?;
+        tokio::fs::write(&meta, serde_json::to_vec_pretty(&sidecar)?).await?;
+        Ok(from_store(data)?.0)
+    }
+
+    async fn delete(&self, cache_key: &str) -> Result<()> {
+        let (record, meta) = self.paths(cache_key);
+        ignore_missing(tokio::fs::remove_file(&record).await)?;
+        ignore_missing(tokio::fs::remove_file(&meta).await)?;
+        Ok(())
+    }
+
+    async fn keys(&self) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut entries = match tokio::fs::read_dir(&self.root).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(keys),
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().map_or(true, |ext| ext != "json") {
+                continue;
+            }
+            // Sidecars aren't written atomically, those removed or half written by
+            // a concurrent call are skipped like undecodable records are by `get`.
+            let bytes = match tokio::fs::read(&path).await {
+                Ok(bytes) => bytes,
+                Err(_e) => continue,
+            };
+            if let Ok(sidecar) = serde_json::from_slice::<Sidecar>(&bytes) {
+                keys.push(sidecar.key);
+            }
+        }
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use http::{Method, Response};
+    use reqwest::Request;
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn can_cache_response() -> Result<()> {
+        let url = reqwest::Url::from_str("https://example.com")?;
+        let res = Response::new("test");
+        let res = reqwest::Response::from(res);
+        let key = format!("GET:{}", &url);
+        let req = Request::new(Method::GET, url);
+        let policy = CachePolicy::new(&req, &res);
+        let manager = FsManager::new("./reqwest-fs-cache-test");
+        manager.put(&key, res, policy).await?;
+        assert_eq!(manager.keys().await?, vec![key.clone()]);
+        let (_, meta) = manager.paths(&key);
+        let sidecar: Sidecar = serde_json::from_slice(&std::fs::read(meta)?)?;
+        assert_eq!(sidecar.url, "https://example.com/");
+        let data = manager.get(&key).await?;
+        let body = match data {
+            Some(d) => d.0.text().await?,
+            None => String::new(),
+        };
+        assert_eq!(&body, "test");
+        manager.delete(&key).await?;
+        let data = manager.get(&key).await?;
+        assert!(data.is_none());
+        manager.clear().await?;
+        assert!(manager.keys().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn skips_unreadable_sidecars() -> Result<()> {
+        let url = reqwest::Url::from_str("https://example.com/sidecar")?;
+        let res = reqwest::Response::from(Response::new("test"));
+        let key = format!("GET:{}", &url);
+        let req = Request::new(Method::GET, url);
+        let policy = CachePolicy::new(&req, &res);
+        let manager = FsManager::new("./reqwest-fs-cache-sidecars");
+        manager.put(&key, res, policy).await?;
+        std::fs::write(
+            manager.root.join("half-written.json"),
+            b"{\"key\": \"GET:htt",
+        )?;
+        assert_eq!(manager.keys().await?, vec![key]);
+        manager.clear().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use crate::{key_has_url_prefix, CacheManager};
+
+use anyhow::{anyhow, Result};
+use http::{HeaderMap, StatusCode, Version};
+use http_cache_semantics::CachePolicy;
+use reqwest::{Response, ResponseBuilderExt, Url};
+
+/// Implements [`CacheManager`] with a [`HashMap`] shared between clones.
+/// Meant for tests and examples, nothing is ever evicted.
+#[derive(Debug, Clone, Default)]
+pub struct HashMapManager {
+    records: Arc<Mutex<HashMap<String, Record>>>,
+}
+
+#[derive(Debug, Clone)]
+struct Record {
+    status: StatusCode,
+    version: Version,
+    headers: HeaderMap,
+    url: Url,
+    body: Vec<u8>,
+    policy: CachePolicy,
+}
+
+impl Record {
+    fn response(&self) -> Result<Response> {
+        let mut res = http::Response::builder()
+            .status(self.status)
+            .version(self.version)
+            .url(self.url.clone())
+            .body(self.body.clone())?;
+        *res.headers_mut() = self.headers.clone();
+        Ok(Response::from(res))
+    }
+}
+
+impl HashMapManager {
+    /// Creates a new, empty manager.
+    pub fn new() -> Self {
+        HashMapManager::default()
+    }
+
+    fn records(&self) -> Result<MutexGuard<'_, HashMap<String, Record>>> {
+        self.records
+            .lock()
+            .map_err(|_| anyhow!("HashMapManager lock was poisoned"))
+    }
+
+    /// Number of records currently stored.
+    pub fn len(&self) -> usize {
+        self.records().map_or(0, |records| records.len())
+    }
+
+    /// Whether no records are stored.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether a record is stored under `cache_key`.
+    pub fn contains_key(&self, cache_key: &str) -> bool {
+        self.records()
+            .map_or(false, |records| records.contains_key(cache_key))
+    }
+
+    /// Clears out the entire cache.
+    pub async fn clear(&self) -> Result<()> {
+        self.records()?.clear();
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl CacheManager for HashMapManager {
+    async fn get(&self, cache_key: &str) -> Result<Option<(Response, CachePolicy)>> {
+        let record = match self.records()?.get(cache_key) {
+            Some(record) => record.clone(),
+            None => return Ok(None),
+        };
+        Ok(Some((record.response()?, record.policy)))
+    }
+
+    async fn put(&self, cache_key: &str, res: Response, policy: CachePolicy) -> Result<Response> {
+        let status = res.status();
+        let version = res.version();
+        let headers = res.headers().clone();
+        let url = res.url().clone();
+        let body = res.bytes().await?.to_vec();
+        let record = Record {
+            status,
+            version,
+            headers,
+            url,
+            body,
+            policy,
+        };
+        let res = record.response()?;
+        self.records()?.insert(cache_key.to_owned(), record);
+        Ok(res)
+    }
+
+    async fn delete(&self, cache_key: &str) -> Result<()> {
+        self.records()?.remove(cache_key);
+        Ok(())
+    }
+
+    async fn keys(&self) -> Result<Vec<String>> {
+        Ok(self.records()?.keys().cloned().collect())
+    }
+
+    async fn invalidate_prefix(&self, prefix: &str) -> Result<()> {
+        self.records()?
+            .retain(|key, _| !key_has_url_prefix(key, prefix));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use http::{Method, Response};
+    use reqwest::Request;
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn can_cache_response() -> Result<()> {
+        let url = reqwest::Url::from_str("https://example.com")?;
+        let res = Response::new("test");
+        let res = reqwest::Response::from(res);
+        let key = format!("GET:{}", &url);
+        let req = Request::new(Method::GET, url);
+        let policy = CachePolicy::new(&req, &res);
+        let manager = HashMapManager::default();
+        manager.put(&key, res, policy).await?;
+        assert!(manager.clone().contains_key(&key));
+        assert_eq!(manager.keys().await?, vec![key.clone()]);
+        let data = manager.get(&key).await?;
+        let body = match data {
+            Some(d) => d.0.text().await?,
+            None => String::new(),
+        };
+        assert_eq!(&body, "test");
+        manager.delete(&key).await?;
+        let data = manager.get(&key).await?;
+        assert!(data.is_none());
+        assert!(manager.is_empty());
+        Ok(())
+    }
+}
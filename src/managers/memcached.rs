@@ -0,0 +1,175 @@
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::store::{decode_store, encode_store, from_store, to_store, Store};
+use crate::CacheManager;
+
+use anyhow::{anyhow, Result};
+use async_memcached::{Client, Error, Status};
+use http_cache_semantics::CachePolicy;
+use reqwest::Response;
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+
+// Longest key memcached accepts.
+const MAX_KEY_LEN: usize = 250;
+
+// Expiration times longer than this are taken by memcached as a Unix timestamp.
+const MAX_RELATIVE_TTL: u64 = 30 * 24 * 60 * 60;
+
+/// Implements [`CacheManager`] with [`memcached`](https://memcached.org/) as the backend,
+/// through [`async-memcached`](https://github.com/tobz/async-memcached).
+///
+/// Entries expire in memcached once they go stale. Records larger than
+/// [`max_value_size`](MemcachedManager::max_value_size) are not stored, the response
+/// is handed back as if caching it had succeeded. Memcached can't list its keys,
+/// so neither [`keys`](CacheManager::keys) nor
+/// [`invalidate_prefix`](CacheManager::invalidate_prefix) are supported.
+#[derive(Clone)]
+pub struct MemcachedManager {
+    client: Arc<Mutex<Client>>,
+    /// Prepended to every key so multiple applications can share a memcached instance.
+    pub prefix: Option<String>,
+    /// Largest record written, memcached's default item size limit of 1MiB by default.
+    pub max_value_size: usize,
+}
+
+impl fmt::Debug for MemcachedManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemcachedManager")
+            .field("prefix", &self.prefix)
+            .field("max_value_size", &self.max_value_size)
+            .finish_non_exhaustive()
+    }
+}
+
+impl MemcachedManager {
+    /// Connects to the memcached server at `dsn`, e.g. `tcp://127.0.0.1:11211`.
+    pub async fn new(dsn: &str, prefix: Option<String>) -> Result<Self> {
+        let client = Client::new(dsn).await?;
+        Ok(MemcachedManager {
+            client: Arc::new(Mutex::new(client)),
+            prefix,
+            max_value_size: 1024 * 1024,
+        })
+    }
+
+    // Memcached keys are limited in length and can't hold whitespace or control
+    // characters, keys that don't fit are replaced by their SHA-256 digest.
+    fn key(&self, cache_key: &str) -> String {
+        let key = match &self.prefix {
+            Some(prefix) => format!("{}{}", prefix, cache_key),
+            None => cache_key.to_owned(),
+        };
+        if key.len() <= MAX_KEY_LEN && !key.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return key;
+        }
+        let digest = Sha256::digest(key.as_bytes());
+        let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+        format!("{}{}", self.prefix.as_deref().unwrap_or_default(), hex)
+    }
+}
+
+// The expiration sent to memcached for a record staying fresh for `ttl`. At least
+// a second so responses that are stale on arrival still round trip, and given as
+// a timestamp beyond the 30 days memcached takes as relative.
+fn expiration(ttl: Duration) -> Result<i64> {
+    let secs = ttl.as_secs().max(1);
+    if secs <= MAX_RELATIVE_TTL {
+        return Ok(secs as i64);
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    Ok((now + secs) as i64)
+}
+
+#[async_trait::async_trait]
+impl CacheManager for MemcachedManager {
+    async fn get(&self, cache_key: &str) -> Result<Option<(Response, CachePolicy)>> {
+        let value = self.client.lock().await.get(self.key(cache_key)).await?;
+        let store: Store = match value {
+            Some(value) => match decode_store(cache_key, &value.data) {
+                Some(store) => store,
+                None => {
+                    self.delete(cache_key).await?;
+                    return Ok(None);
+                }
+            },
+            None => return Ok(None),
+        };
+        Ok(Some(from_store(store)?))
+    }
+
+    async fn put(&self, cache_key: &str, res: Response, policy: CachePolicy) -> Result<Response> {
+        let ttl = expiration(policy.time_to_live(SystemTime::now()))?;
+        let data = to_store(res, policy).await?;
+        let bytes = encode_store(&data)?;
+        if bytes.len() > self.max_value_size {
+            cache_event!(
+                key = cache_key,
+                size = bytes.len(),
+                "record too large for memcached, not stored"
+            );
+            return Ok(from_store(data)?.0);
+        }
+        self.client
+            .lock()
+            .await
+            .set(self.key(cache_key), &bytes[..], Some(ttl), None)
+            .await?;
+        Ok(from_store(data)?.0)
+    }
+
+    async fn delete(&self, cache_key: &str) -> Result<()> {
+        match self.client.lock().await.delete(self.key(cache_key)).await {
+            Ok(()) | Err(Error::Protocol(Status::NotFound)) => Ok(()),
+            Err(e) => Err(anyhow!(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use http::{Method, Response};
+    use reqwest::Request;
+    use std::str::FromStr;
+
+    #[tokio::test]
+    #[ignore = "requires a memcached server listening on localhost"]
+    async fn can_cache_response() -> Result<()> {
+        let url = reqwest::Url::from_str("https://example.com")?;
+        let res = Response::new("test");
+        let res = reqwest::Response::from(res);
+        let key = format!("GET:{}", &url);
+        let req = Request::new(Method::GET, url);
+        let policy = CachePolicy::new(&req, &res);
+        let manager =
+            MemcachedManager::new("tcp://127.0.0.1:11211", Some("reqwest-cache-test:".into()))
+                .await?;
+        manager.put(&key, res, policy).await?;
+        let data = manager.get(&key).await?;
+        let body = match data {
+            Some(d) => d.0.text().await?,
+            None => String::new(),
+        };
+        assert_eq!(&body, "test");
+        manager.delete(&key).await?;
+        let data = manager.get(&key).await?;
+        assert!(data.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn long_expirations_are_timestamps() -> Result<()> {
+        assert_eq!(expiration(Duration::from_secs(0))?, 1);
+        assert_eq!(expiration(Duration::from_secs(3600))?, 3600);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let year = 365 * 24 * 60 * 60;
+        assert!(expiration(Duration::from_secs(year as u64))? >= now + year);
+        Ok(())
+    }
+}
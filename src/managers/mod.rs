@@ -1,5 +1,79 @@
+#[cfg(any(
+    feature = "manager-cacache",
+    feature = "manager-moka",
+    feature = "manager-redis",
+    feature = "manager-sqlite",
+    feature = "manager-fs",
+    feature = "manager-dynamodb",
+    feature = "manager-sled",
+    feature = "manager-memcached"
+))]
+mod store;
+
+#[cfg(any(
+    feature = "manager-cacache",
+    feature = "manager-moka",
+    feature = "manager-redis",
+    feature = "manager-sqlite",
+    feature = "manager-fs",
+    feature = "manager-dynamodb",
+    feature = "manager-sled",
+    feature = "manager-memcached"
+))]
+pub use self::store::Serialization;
+
 #[cfg(feature = "manager-cacache")]
 mod cacache;
 
+#[cfg(feature = "manager-moka")]
+mod moka;
+
+#[cfg(feature = "manager-redis")]
+mod redis;
+
+#[cfg(feature = "manager-sqlite")]
+mod sqlite;
+
+#[cfg(feature = "manager-fs")]
+mod fs;
+
+#[cfg(feature = "manager-dynamodb")]
+mod dynamodb;
+
+#[cfg(feature = "manager-sled")]
+mod sled;
+
+#[cfg(feature = "manager-memcached")]
+mod memcached;
+
+mod hashmap;
+mod recording;
+mod tiered;
+
 #[cfg(feature = "manager-cacache")]
 pub use self::cacache::CACacheManager;
+
+#[cfg(feature = "manager-moka")]
+pub use self::moka::MokaManager;
+
+#[cfg(feature = "manager-redis")]
+pub use self::redis::RedisManager;
+
+#[cfg(feature = "manager-sqlite")]
+pub use self::sqlite::SqliteManager;
+
+#[cfg(feature = "manager-fs")]
+pub use self::fs::FsManager;
+
+#[cfg(feature = "manager-dynamodb")]
+pub use self::dynamodb::DynamoDbManager;
+
+#[cfg(feature = "manager-sled")]
+pub use self::sled::SledManager;
+
+#[cfg(feature = "manager-memcached")]
+pub use self::memcached::MemcachedManager;
+
+pub use self::hashmap::HashMapManager;
+pub use self::recording::{CacheOp, RecordingManager};
+pub use self::tiered::TieredManager;
@@ -0,0 +1,102 @@
+use super::store::{decode_store, encode_store, from_store, to_store, Store};
+use crate::CacheManager;
+
+use anyhow::Result;
+use http_cache_semantics::CachePolicy;
+use moka::future::Cache;
+use reqwest::Response;
+
+/// Implements [`CacheManager`] with [`moka`](https://github.com/moka-rs/moka) as the backend.
+/// Nothing is written to disk, the cache only lives as long as the manager (and its clones).
+#[derive(Debug, Clone)]
+pub struct MokaManager {
+    /// In-memory cache holding the serialized records, keyed by request.
+    pub cache: Cache<String, Vec<u8>>,
+}
+
+impl Default for MokaManager {
+    fn default() -> Self {
+        MokaManager::new(10_000)
+    }
+}
+
+impl MokaManager {
+    /// Creates a new manager that holds at most `max_capacity` entries.
+    pub fn new(max_capacity: u64) -> Self {
+        MokaManager {
+            cache: Cache::new(max_capacity),
+        }
+    }
+
+    /// Clears out the entire cache.
+    pub async fn clear(&self) -> Result<()> {
+        self.cache.invalidate_all();
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl CacheManager for MokaManager {
+    async fn get(&self, cache_key: &str) -> Result<Option<(Response, CachePolicy)>> {
+        let store: Store = match self.cache.get(cache_key) {
+            Some(d) => match decode_store(cache_key, &d) {
+                Some(store) => store,
+                None => {
+                    self.delete(cache_key).await?;
+                    return Ok(None);
+                }
+            },
+            None => return Ok(None),
+        };
+        Ok(Some(from_store(store)?))
+    }
+
+    async fn put(&self, cache_key: &str, res: Response, policy: CachePolicy) -> Result<Response> {
+        let data = to_store(res, policy).await?;
+        let bytes = encode_store(&data)?;
+        self.cache.insert(cache_key.to_owned(), bytes).await;
+        Ok(from_store(data)?.0)
+    }
+
+    async fn delete(&self, cache_key: &str) -> Result<()> {
+        self.cache.invalidate(cache_key).await;
+        Ok(())
+    }
+
+    async fn keys(&self) -> Result<Vec<String>> {
+        Ok(self.cache.iter().map(|(key, _)| key.to_string()).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use http::{Method, Response};
+    use reqwest::Request;
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn can_cache_response() -> Result<()> {
+        let url = reqwest::Url::from_str("https://example.com")?;
+        let res = Response::new("test");
+        let res = reqwest::Response::from(res);
+        let key = format!("GET:{}", &url);
+        let req = Request::new(Method::GET, url);
+        let policy = CachePolicy::new(&req, &res);
+        let manager = MokaManager::default();
+        manager.put(&key, res, policy).await?;
+        assert_eq!(manager.keys().await?, vec![key.clone()]);
+        let data = manager.get(&key).await?;
+        let body = match data {
+            Some(d) => d.0.text().await?,
+            None => String::new(),
+        };
+        assert_eq!(&body, "test");
+        manager.delete(&key).await?;
+        let data = manager.get(&key).await?;
+        assert!(data.is_none());
+        manager.clear().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::{CacheManager, CacheStats};
+
+use anyhow::Result;
+use http_cache_semantics::CachePolicy;
+use reqwest::Response;
+
+/// A call made to a [`RecordingManager`], with the key or prefix it was given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CacheOp {
+    /// [`CacheManager::get`]
+    Get(String),
+    /// [`CacheManager::get_policy`]
+    GetPolicy(String),
+    /// [`CacheManager::put`]
+    Put(String),
+    /// [`CacheManager::update_metadata`]
+    UpdateMetadata(String),
+    /// [`CacheManager::delete`]
+    Delete(String),
+    /// [`CacheManager::keys`]
+    Keys,
+    /// [`CacheManager::invalidate_prefix`]
+    InvalidatePrefix(String),
+    /// [`CacheManager::stats`]
+    Stats,
+    /// [`CacheManager::clear_expired`]
+    ClearExpired,
+}
+
+/// Implements [`CacheManager`] by delegating to another manager, recording every
+/// call made to it. Meant for tests asserting how the middleware uses its manager,
+/// clones share the same log.
+///
+/// ```no_run
+/// # use reqwest::Client;
+/// # use reqwest_middleware::ClientBuilder;
+/// # use reqwest_middleware_cache::managers::{CacheOp, HashMapManager, RecordingManager};
+/// # use reqwest_middleware_cache::Cache;
+/// # async fn run() -> anyhow::Result<()> {
+/// let manager = RecordingManager::new(HashMapManager::default());
+/// let client = ClientBuilder::new(Client::new())
+///     .with(Cache::builder().cache_manager(manager.clone()).build()?)
+///     .build();
+/// client.get("https://example.com").send().await?;
+/// assert_eq!(manager.ops()[0], CacheOp::Get("GET:https://example.com/".into()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct RecordingManager<M> {
+    /// The manager every call is delegated to.
+    pub inner: M,
+    /// The calls made so far, oldest first.
+    pub log: Arc<Mutex<Vec<CacheOp>>>,
+}
+
+impl<M> RecordingManager<M> {
+    /// Creates a manager recording the calls it delegates to `inner`.
+    pub fn new(inner: M) -> Self {
+        RecordingManager {
+            inner,
+            log: Arc::default(),
+        }
+    }
+
+    /// A copy of the calls made so far, oldest first.
+    pub fn ops(&self) -> Vec<CacheOp> {
+        self.log
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Forgets the calls made so far.
+    pub fn clear_ops(&self) {
+        self.log
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    fn record(&self, op: CacheOp) {
+        self.log
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(op);
+    }
+}
+
+#[async_trait::async_trait]
+impl<M: CacheManager + Send + Sync> CacheManager for RecordingManager<M> {
+    async fn get(&self, cache_key: &str) -> Result<Option<(Response, CachePolicy)>> {
+        self.record(CacheOp::Get(cache_key.to_owned()));
+        self.inner.get(cache_key).await
+    }
+
+    async fn get_policy(&self, cache_key: &str) -> Result<Option<CachePolicy>> {
+        self.record(CacheOp::GetPolicy(cache_key.to_owned()));
+        self.inner.get_policy(cache_key).await
+    }
+
+    async fn put(&self, cache_key: &str, res: Response, policy: CachePolicy) -> Result<Response> {
+        self.record(CacheOp::Put(cache_key.to_owned()));
+        self.inner.put(cache_key, res, policy).await
+    }
+
+    async fn update_metadata(
+        &self,
+        cache_key: &str,
+        res: Response,
+        policy: CachePolicy,
+    ) -> Result<Response> {
+        self.record(CacheOp::UpdateMetadata(cache_key.to_owned()));
+        self.inner.update_metadata(cache_key, res, policy).await
+    }
+
+    async fn delete(&self, cache_key: &str) -> Result<()> {
+        self.record(CacheOp::Delete(cache_key.to_owned()));
+        self.inner.delete(cache_key).await
+    }
+
+    async fn keys(&self) -> Result<Vec<String>> {
+        self.record(CacheOp::Keys);
+        self.inner.keys().await
+    }
+
+    async fn invalidate_prefix(&self, prefix: &str) -> Result<()> {
+        self.record(CacheOp::InvalidatePrefix(prefix.to_owned()));
+        self.inner.invalidate_prefix(prefix).await
+    }
+
+    async fn stats(&self) -> Result<CacheStats> {
+        self.record(CacheOp::Stats);
+        self.inner.stats().await
+    }
+
+    async fn clear_expired(&self) -> Result<usize> {
+        self.record(CacheOp::ClearExpired);
+        self.inner.clear_expired().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::managers::HashMapManager;
+    use anyhow::Result;
+    use http::{Method, Response};
+    use reqwest::Request;
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn records_calls_in_order() -> Result<()> {
+        let url = reqwest::Url::from_str("https://example.com/recorded")?;
+        let res = reqwest::Response::from(Response::new("test"));
+        let key = format!("GET:{}", &url);
+        let req = Request::new(Method::GET, url);
+        let policy = CachePolicy::new(&req, &res);
+        let manager = RecordingManager::new(HashMapManager::new());
+        manager.put(&key, res, policy).await?;
+        manager.get(&key).await?;
+        manager.delete(&key).await?;
+        assert_eq!(
+            manager.ops(),
+            vec![
+                CacheOp::Put(key.clone()),
+                CacheOp::Get(key.clone()),
+                CacheOp::Delete(key),
+            ]
+        );
+        manager.clear_ops();
+        assert!(manager.ops().is_empty());
+        Ok(())
+    }
+}
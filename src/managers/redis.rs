@@ -0,0 +1,146 @@
+use std::{fmt, time::SystemTime};
+
+use super::store::{decode_store, encode_store, from_store, to_store, Store};
+use crate::CacheManager;
+
+use anyhow::Result;
+use http_cache_semantics::CachePolicy;
+use redis::{aio::ConnectionManager, AsyncCommands};
+use reqwest::Response;
+
+/// Implements [`CacheManager`] with [`redis`](https://github.com/mitsuhiko/redis-rs) as the backend.
+/// Lets several processes share the same cache.
+#[derive(Clone)]
+pub struct RedisManager {
+    connection: ConnectionManager,
+    /// Prepended to every key so multiple applications can share a Redis instance.
+    pub prefix: Option<String>,
+}
+
+impl fmt::Debug for RedisManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedisManager")
+            .field("prefix", &self.prefix)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RedisManager {
+    /// Connects to the Redis server at `connection_string`, e.g. `redis://127.0.0.1/`.
+    pub async fn new(connection_string: &str, prefix: Option<String>) -> Result<Self> {
+        let client = redis::Client::open(connection_string)?;
+        let connection = ConnectionManager::new(client).await?;
+        Ok(RedisManager { connection, prefix })
+    }
+
+    fn key(&self, cache_key: &str) -> String {
+        match &self.prefix {
+            Some(prefix) => format!("{}{}", prefix, cache_key),
+            None => cache_key.to_owned(),
+        }
+    }
+
+    // Every Redis key under the prefix, prefix included.
+    async fn scan(&self) -> Result<Vec<String>> {
+        let mut conn = self.connection.clone();
+        let pattern = format!("{}*", self.prefix.as_deref().unwrap_or_default());
+        let mut keys: Vec<String> = Vec::new();
+        let mut iter = conn.scan_match::<_, String>(pattern).await?;
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+        Ok(keys)
+    }
+
+    /// Clears out the entire cache.
+    /// When no prefix is set this removes every key in the selected database.
+    pub async fn clear(&self) -> Result<()> {
+        let keys = self.scan().await?;
+        if !keys.is_empty() {
+            let mut conn = self.connection.clone();
+            conn.del::<_, ()>(keys).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl CacheManager for RedisManager {
+    async fn get(&self, cache_key: &str) -> Result<Option<(Response, CachePolicy)>> {
+        let mut conn = self.connection.clone();
+        let data: Option<Vec<u8>> = conn.get(self.key(cache_key)).await?;
+        let store: Store = match data {
+            Some(d) => match decode_store(cache_key, &d) {
+                Some(store) => store,
+                None => {
+                    self.delete(cache_key).await?;
+                    return Ok(None);
+                }
+            },
+            None => return Ok(None),
+        };
+        Ok(Some(from_store(store)?))
+    }
+
+    async fn put(&self, cache_key: &str, res: Response, policy: CachePolicy) -> Result<Response> {
+        let mut conn = self.connection.clone();
+        // Let Redis evict the entry once it goes stale, keeping at least a second
+        // so responses that are stale on arrival still round trip.
+        let ttl = policy.time_to_live(SystemTime::now()).as_secs().max(1);
+        let data = to_store(res, policy).await?;
+        let bytes = encode_store(&data)?;
+        conn.set_ex::<_, _, ()>(self.key(cache_key), bytes, ttl as usize)
+            .await?;
+        Ok(from_store(data)?.0)
+    }
+
+    async fn delete(&self, cache_key: &str) -> Result<()> {
+        let mut conn = self.connection.clone();
+        conn.del::<_, ()>(self.key(cache_key)).await?;
+        Ok(())
+    }
+
+    async fn keys(&self) -> Result<Vec<String>> {
+        let prefix = self.prefix.as_deref().unwrap_or_default();
+        Ok(self
+            .scan()
+            .await?
+            .into_iter()
+            .map(|key| key[prefix.len()..].to_owned())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use http::{Method, Response};
+    use reqwest::Request;
+    use std::str::FromStr;
+
+    #[tokio::test]
+    #[ignore = "requires a Redis server listening on localhost"]
+    async fn can_cache_response() -> Result<()> {
+        let url = reqwest::Url::from_str("https://example.com")?;
+        let res = Response::new("test");
+        let res = reqwest::Response::from(res);
+        let key = format!("GET:{}", &url);
+        let req = Request::new(Method::GET, url);
+        let policy = CachePolicy::new(&req, &res);
+        let manager =
+            RedisManager::new("redis://127.0.0.1/", Some("reqwest-cache-test:".into())).await?;
+        manager.put(&key, res, policy).await?;
+        let data = manager.get(&key).await?;
+        let body = match data {
+            Some(d) => d.0.text().await?,
+            None => String::new(),
+        };
+        assert_eq!(&body, "test");
+        manager.delete(&key).await?;
+        let data = manager.get(&key).await?;
+        assert!(data.is_none());
+        manager.clear().await?;
+        Ok(())
+    }
+}
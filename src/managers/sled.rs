@@ -0,0 +1,116 @@
+use super::store::{decode_store, encode_store, from_store, to_store, Store};
+use crate::{key_has_url_prefix, CacheManager};
+
+use anyhow::Result;
+use http_cache_semantics::CachePolicy;
+use reqwest::Response;
+use sled::Tree;
+
+/// Implements [`CacheManager`] with [`sled`](https://github.com/spacejam/sled) as the backend.
+/// Records are kept in a tree of an existing database, so the cache can share it
+/// with other state. Writes are made durable by sled's periodic flushes, or right
+/// away by calling [`flush`](SledManager::flush).
+#[derive(Debug, Clone)]
+pub struct SledManager {
+    /// Tree holding the serialized records, keyed by request.
+    pub tree: Tree,
+}
+
+impl SledManager {
+    /// Creates a new manager storing records in `tree`.
+    pub fn new(tree: Tree) -> Self {
+        SledManager { tree }
+    }
+
+    /// Clears out the entire cache.
+    pub async fn clear(&self) -> Result<()> {
+        self.tree.clear()?;
+        Ok(())
+    }
+
+    /// Writes every pending change to disk, returning the number of bytes flushed.
+    pub async fn flush(&self) -> Result<usize> {
+        Ok(self.tree.flush_async().await?)
+    }
+}
+
+#[async_trait::async_trait]
+impl CacheManager for SledManager {
+    async fn get(&self, cache_key: &str) -> Result<Option<(Response, CachePolicy)>> {
+        let store: Store = match self.tree.get(cache_key)? {
+            Some(d) => match decode_store(cache_key, &d) {
+                Some(store) => store,
+                None => {
+                    self.delete(cache_key).await?;
+                    return Ok(None);
+                }
+            },
+            None => return Ok(None),
+        };
+        Ok(Some(from_store(store)?))
+    }
+
+    async fn put(&self, cache_key: &str, res: Response, policy: CachePolicy) -> Result<Response> {
+        let data = to_store(res, policy).await?;
+        let bytes = encode_store(&data)?;
+        self.tree.insert(cache_key, bytes)?;
+        Ok(from_store(data)?.0)
+    }
+
+    async fn delete(&self, cache_key: &str) -> Result<()> {
+        self.tree.remove(cache_key)?;
+        Ok(())
+    }
+
+    async fn keys(&self) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for key in self.tree.iter().keys() {
+            keys.push(String::from_utf8_lossy(&key?).into_owned());
+        }
+        Ok(keys)
+    }
+
+    async fn invalidate_prefix(&self, prefix: &str) -> Result<()> {
+        for key in self.keys().await? {
+            if key_has_url_prefix(&key, prefix) {
+                self.delete(&key).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use http::{Method, Response};
+    use reqwest::Request;
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn can_cache_response() -> Result<()> {
+        let url = reqwest::Url::from_str("https://example.com")?;
+        let res = Response::new("test");
+        let res = reqwest::Response::from(res);
+        let key = format!("GET:{}", &url);
+        let req = Request::new(Method::GET, url);
+        let policy = CachePolicy::new(&req, &res);
+        let db = sled::Config::new().temporary(true).open()?;
+        let manager = SledManager::new(db.open_tree("http-cache")?);
+        manager.put(&key, res, policy).await?;
+        assert_eq!(manager.keys().await?, vec![key.clone()]);
+        manager.flush().await?;
+        let data = manager.get(&key).await?;
+        let body = match data {
+            Some(d) => d.0.text().await?,
+            None => String::new(),
+        };
+        assert_eq!(&body, "test");
+        manager.delete(&key).await?;
+        let data = manager.get(&key).await?;
+        assert!(data.is_none());
+        manager.clear().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::store::{decode_store, encode_store, from_store, to_store};
+use crate::CacheManager;
+
+use anyhow::{anyhow, Result};
+use http_cache_semantics::CachePolicy;
+use reqwest::Response;
+use rusqlite::{params, Connection, OptionalExtension};
+
+/// Implements [`CacheManager`] with [`SQLite`](https://github.com/rusqlite/rusqlite) as the backend.
+/// Every record lives in a single database file, along with the time it goes stale
+/// so expired records can be pruned cheaply.
+#[derive(Debug, Clone)]
+pub struct SqliteManager {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteManager {
+    /// Opens (or creates) the database at `path`, `":memory:"` keeps it in memory.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS cache (
+                key TEXT PRIMARY KEY,
+                record BLOB NOT NULL,
+                expires_at INTEGER NOT NULL
+            )",
+            [],
+        )?;
+        Ok(SqliteManager {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    // Runs `f` against the connection on the blocking thread pool.
+    async fn with_connection<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&Connection) -> rusqlite::Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            let connection = connection
+                .lock()
+                .map_err(|_| anyhow!("SqliteManager connection lock was poisoned"))?;
+            Ok(f(&connection)?)
+        })
+        .await?
+    }
+
+    /// Clears out the entire cache.
+    pub async fn clear(&self) -> Result<()> {
+        self.with_connection(|conn| conn.execute("DELETE FROM cache", []))
+            .await?;
+        Ok(())
+    }
+
+    /// Removes every record that has gone stale, returning how many were removed.
+    /// Stale records can still be revalidated, so this trades fewer conditional
+    /// requests for a smaller database.
+    pub async fn prune_expired(&self) -> Result<usize> {
+        let now = unix_time(SystemTime::now());
+        self.with_connection(move |conn| {
+            conn.execute("DELETE FROM cache WHERE expires_at <= ?1", params![now])
+        })
+        .await
+    }
+}
+
+fn unix_time(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() as i64)
+}
+
+#[async_trait::async_trait]
+impl CacheManager for SqliteManager {
+    async fn get(&self, cache_key: &str) -> Result<Option<(Response, CachePolicy)>> {
+        let key = cache_key.to_owned();
+        let record: Option<Vec<u8>> = self
+            .with_connection(move |conn| {
+                conn.query_row(
+                    "SELECT record FROM cache WHERE key = ?1",
+                    params![key],
+                    |row| row.get(0),
+                )
+                .optional()
+            })
+            .await?;
+        let store = match record {
+            Some(d) => match decode_store(cache_key, &d) {
+                Some(store) => store,
+                None => {
+                    self.delete(cache_key).await?;
+                    return Ok(None);
+                }
+            },
+            None => return Ok(None),
+        };
+        Ok(Some(from_store(store)?))
+    }
+
+    async fn put(&self, cache_key: &str, res: Response, policy: CachePolicy) -> Result<Response> {
+        let now = SystemTime::now();
+        let expires_at = unix_time(now + policy.time_to_live(now));
+        let data = to_store(res, policy).await?;
+        let record = encode_store(&data)?;
+        let key = cache_key.to_owned();
+        self.with_connection(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO cache (key, record, expires_at) VALUES (?1, ?2, ?3)",
+                params![key, record, expires_at],
+            )
+        })
+        .await?;
+        Ok(from_store(data)?.0)
+    }
+
+    async fn delete(&self, cache_key: &str) -> Result<()> {
+        let key = cache_key.to_owned();
+        self.with_connection(move |conn| {
+            conn.execute("DELETE FROM cache WHERE key = ?1", params![key])
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn keys(&self) -> Result<Vec<String>> {
+        self.with_connection(|conn| {
+            let mut statement = conn.prepare("SELECT key FROM cache")?;
+            let keys = statement
+                .query_map([], |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<String>>>();
+            keys
+        })
+        .await
+    }
+
+    async fn clear_expired(&self) -> Result<usize> {
+        self.prune_expired().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use http::{Method, Response};
+    use reqwest::Request;
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn can_cache_response() -> Result<()> {
+        let url = reqwest::Url::from_str("https://example.com")?;
+        let res = Response::new("test");
+        let res = reqwest::Response::from(res);
+        let key = format!("GET:{}", &url);
+        let req = Request::new(Method::GET, url);
+        let policy = CachePolicy::new(&req, &res);
+        let manager = SqliteManager::new(":memory:")?;
+        manager.put(&key, res, policy).await?;
+        assert_eq!(manager.keys().await?, vec![key.clone()]);
+        let data = manager.get(&key).await?;
+        let body = match data {
+            Some(d) => d.0.text().await?,
+            None => String::new(),
+        };
+        assert_eq!(&body, "test");
+        // Without any freshness information the record is stale right away
+        assert_eq!(manager.prune_expired().await?, 1);
+        let data = manager.get(&key).await?;
+        assert!(data.is_none());
+        manager.clear().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,354 @@
+#[cfg(not(feature = "json"))]
+use anyhow::anyhow;
+use anyhow::{Context, Result};
+use http::version::Version;
+use http_cache_semantics::CachePolicy;
+use reqwest::{
+    header::{HeaderName, HeaderValue},
+    Body, Response, ResponseBuilderExt,
+};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::CacheError;
+
+// HTTP version enum in the http crate does not support serde, hence the modified copy.
+#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
+pub(crate) enum HttpVersion {
+    #[serde(rename = "HTTP/0.9")]
+    Http09,
+    #[serde(rename = "HTTP/1.0")]
+    Http10,
+    #[serde(rename = "HTTP/1.1")]
+    Http11,
+    #[serde(rename = "HTTP/2.0")]
+    H2,
+    #[serde(rename = "HTTP/3.0")]
+    H3,
+}
+
+// The version rarely matters to whoever reads the cached response, so one the
+// enum doesn't know about is stored as HTTP/1.1 rather than failing the write.
+impl From<Version> for HttpVersion {
+    fn from(value: Version) -> Self {
+        match value {
+            Version::HTTP_09 => HttpVersion::Http09,
+            Version::HTTP_10 => HttpVersion::Http10,
+            Version::HTTP_11 => HttpVersion::Http11,
+            Version::HTTP_2 => HttpVersion::H2,
+            Version::HTTP_3 => HttpVersion::H3,
+            _ => {
+                cache_event!(version = ?value, "storing unknown HTTP version as HTTP/1.1");
+                HttpVersion::Http11
+            }
+        }
+    }
+}
+
+impl From<HttpVersion> for Version {
+    fn from(value: HttpVersion) -> Self {
+        match value {
+            HttpVersion::Http09 => Version::HTTP_09,
+            HttpVersion::Http10 => Version::HTTP_10,
+            HttpVersion::Http11 => Version::HTTP_11,
+            HttpVersion::H2 => Version::HTTP_2,
+            HttpVersion::H3 => Version::HTTP_3,
+        }
+    }
+}
+
+/// The record written to the backend for every cached response.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Store {
+    pub(crate) response: StoredResponse,
+    pub(crate) policy: CachePolicy,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct StoredResponse {
+    pub(crate) body: Vec<u8>,
+    // Header names and values are kept as the raw bytes handed to us by the http crate,
+    // values are not required to be valid UTF-8.
+    pub(crate) headers: Vec<(Vec<u8>, Vec<u8>)>,
+    pub(crate) status: u16,
+    pub(crate) url: Url,
+    pub(crate) version: HttpVersion,
+}
+
+/// Captures everything but the body, for managers that write the body on its own.
+pub(crate) fn store_parts(res: &Response, policy: CachePolicy) -> Result<Store> {
+    let mut headers = Vec::with_capacity(res.headers().len());
+    for header in res.headers() {
+        headers.push((
+            header.0.as_str().as_bytes().to_vec(),
+            header.1.as_bytes().to_vec(),
+        ));
+    }
+    Ok(Store {
+        response: StoredResponse {
+            body: Vec::new(),
+            headers,
+            status: res.status().as_u16(),
+            url: res.url().clone(),
+            version: res.version().into(),
+        },
+        policy,
+    })
+}
+
+pub(crate) async fn to_store(res: Response, policy: CachePolicy) -> Result<Store> {
+    let mut store = store_parts(&res, policy)?;
+    store.response.body = res.bytes().await?.to_vec();
+    Ok(store)
+}
+
+/// How managers serialize the records they write. Records are read back whichever
+/// way they were written, so the setting can be changed on an existing cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Serialization {
+    /// Compact binary records using [`bincode`](https://github.com/bincode-org/bincode),
+    /// the default.
+    Bincode,
+    /// Pretty-printed JSON records that can be read and edited by hand, requires the
+    /// `json` feature.
+    Json,
+}
+
+impl Default for Serialization {
+    fn default() -> Self {
+        Serialization::Bincode
+    }
+}
+
+// Version of the record layout, written ahead of every bincode record and as a field
+// of JSON ones. Bump it whenever `Store` or anything it contains changes shape. Its low
+// byte must never be `{`, which is how JSON records are told apart.
+const STORE_VERSION: u16 = 1;
+
+#[cfg(feature = "json")]
+#[derive(Serialize)]
+struct JsonRecordRef<'a> {
+    version: u16,
+    store: &'a Store,
+}
+
+#[cfg(feature = "json")]
+#[derive(Deserialize)]
+struct JsonRecord {
+    version: u16,
+    store: Store,
+}
+
+pub(crate) fn encode_store(store: &Store) -> Result<Vec<u8>> {
+    encode_store_as(store, Serialization::Bincode)
+}
+
+pub(crate) fn encode_store_as(store: &Store, serialization: Serialization) -> Result<Vec<u8>> {
+    let encoded = match serialization {
+        Serialization::Bincode => {
+            let mut bytes = STORE_VERSION.to_le_bytes().to_vec();
+            bincode::serialize_into(&mut bytes, store)
+                .map(|_| bytes)
+                .map_err(anyhow::Error::from)
+        }
+        Serialization::Json => encode_json(store),
+    };
+    Ok(encoded.map_err(CacheError::Serialization)?)
+}
+
+#[cfg(feature = "json")]
+fn encode_json(store: &Store) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec_pretty(&JsonRecordRef {
+        version: STORE_VERSION,
+        store,
+    })?)
+}
+
+#[cfg(not(feature = "json"))]
+fn encode_json(_store: &Store) -> Result<Vec<u8>> {
+    Err(anyhow!("JSON serialization requires the `json` feature"))
+}
+
+// Decodes a record, `None` when it is corrupt or was written in an incompatible format,
+// in which case the caller should drop it and treat the lookup as a miss.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn decode_store(cache_key: &str, bytes: &[u8]) -> Option<Store> {
+    if bytes.first() == Some(&b'{') {
+        return decode_json(cache_key, bytes);
+    }
+    if bytes.len() < 2 || bytes[..2] != STORE_VERSION.to_le_bytes() {
+        cache_event!(
+            key = cache_key,
+            "discarding cache record written in another format version"
+        );
+        return None;
+    }
+    match bincode::deserialize(&bytes[2..]) {
+        Ok(store) => Some(store),
+        Err(_e) => {
+            cache_event!(key = cache_key, error = %_e, "discarding cache record that failed to deserialize");
+            None
+        }
+    }
+}
+
+#[cfg(feature = "json")]
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+fn decode_json(cache_key: &str, bytes: &[u8]) -> Option<Store> {
+    match serde_json::from_slice::<JsonRecord>(bytes) {
+        Ok(record) if record.version == STORE_VERSION => Some(record.store),
+        Ok(_) => {
+            cache_event!(
+                key = cache_key,
+                "discarding cache record written in another format version"
+            );
+            None
+        }
+        Err(_e) => {
+            cache_event!(key = cache_key, error = %_e, "discarding cache record that failed to deserialize");
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "json"))]
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+fn decode_json(cache_key: &str, _bytes: &[u8]) -> Option<Store> {
+    cache_event!(
+        key = cache_key,
+        "discarding JSON cache record, reading it requires the `json` feature"
+    );
+    None
+}
+
+/// Rebuilds the response from `store`, moving the stored body into it rather than
+/// copying it.
+pub(crate) fn from_store(mut store: Store) -> Result<(Response, CachePolicy)> {
+    let body = std::mem::take(&mut store.response.body);
+    let res = from_store_with_body(&store, body)?;
+    Ok((res, store.policy))
+}
+
+/// Rebuilds the response from `store`, using `body` in place of the stored one.
+pub(crate) fn from_store_with_body<B: Into<Body>>(store: &Store, body: B) -> Result<Response> {
+    Ok(build_response(store, body).map_err(CacheError::Serialization)?)
+}
+
+fn build_response<B: Into<Body>>(store: &Store, body: B) -> Result<Response> {
+    let mut res = http::Response::builder()
+        .status(store.response.status)
+        .url(store.response.url.clone())
+        .version(store.response.version.into())
+        .body(body.into())?;
+    for (name, value) in &store.response.headers {
+        let name = HeaderName::from_bytes(name).with_context(|| {
+            format!(
+                "Invalid header name in cached record: {:?}",
+                String::from_utf8_lossy(name)
+            )
+        })?;
+        res.headers_mut()
+            .append(name, HeaderValue::from_bytes(value)?);
+    }
+    Ok(Response::from(res))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use http::{
+        header::{CONTENT_DISPOSITION, SET_COOKIE},
+        Method,
+    };
+    use reqwest::Request;
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn keeps_repeated_headers() -> Result<()> {
+        let url = reqwest::Url::from_str("https://example.com")?;
+        let res = http::Response::builder()
+            .header(SET_COOKIE, "a=1")
+            .header(SET_COOKIE, "b=2")
+            .body("test")?;
+        let res = Response::from(res);
+        let req = Request::new(Method::GET, url);
+        let policy = CachePolicy::new(&req, &res);
+        let store = to_store(res, policy).await?;
+        let bytes = encode_store(&store)?;
+        let (res, _) = from_store(decode_store("", &bytes).expect("record should decode"))?;
+        let cookies: Vec<_> = res.headers().get_all(SET_COOKIE).iter().collect();
+        assert_eq!(cookies, vec!["a=1", "b=2"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_other_versions() -> Result<()> {
+        let url = reqwest::Url::from_str("https://example.com")?;
+        let res = Response::from(http::Response::new("test"));
+        let req = Request::new(Method::GET, url);
+        let policy = CachePolicy::new(&req, &res);
+        let mut bytes = encode_store(&to_store(res, policy).await?)?;
+        assert!(decode_store("", &bytes).is_some());
+        bytes[..2].copy_from_slice(&(STORE_VERSION + 1).to_le_bytes());
+        assert!(decode_store("", &bytes).is_none());
+        assert!(decode_store("", &[]).is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_header_names() -> Result<()> {
+        let url = reqwest::Url::from_str("https://example.com")?;
+        let res = Response::from(http::Response::new("test"));
+        let req = Request::new(Method::GET, url);
+        let policy = CachePolicy::new(&req, &res);
+        let mut store = to_store(res, policy).await?;
+        store
+            .response
+            .headers
+            .push((b"X-Mixed-Case".to_vec(), b"ok".to_vec()));
+        let res = from_store_with_body(&store, Vec::new())?;
+        assert_eq!(res.headers()["x-mixed-case"], "ok");
+        store
+            .response
+            .headers
+            .push((b"bad header".to_vec(), b"nope".to_vec()));
+        assert!(from_store(store).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keeps_non_utf8_header_values() -> Result<()> {
+        let url = reqwest::Url::from_str("https://example.com")?;
+        let disposition = HeaderValue::from_bytes(b"attachment; filename=\"caf\xe9.txt\"")?;
+        let res = http::Response::builder()
+            .header(CONTENT_DISPOSITION, disposition.clone())
+            .body("test")?;
+        let res = Response::from(res);
+        let req = Request::new(Method::GET, url);
+        let policy = CachePolicy::new(&req, &res);
+        let store = to_store(res, policy).await?;
+        let bytes = encode_store(&store)?;
+        let (res, _) = from_store(decode_store("", &bytes).expect("record should decode"))?;
+        assert_eq!(res.headers()[CONTENT_DISPOSITION], disposition);
+        Ok(())
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn reads_either_serialization() -> Result<()> {
+        let url = reqwest::Url::from_str("https://example.com")?;
+        let res = Response::from(http::Response::new("test"));
+        let req = Request::new(Method::GET, url);
+        let policy = CachePolicy::new(&req, &res);
+        let store = to_store(res, policy).await?;
+        for serialization in &[Serialization::Bincode, Serialization::Json] {
+            let bytes = encode_store_as(&store, *serialization)?;
+            let (res, _) = from_store(decode_store("", &bytes).expect("record should decode"))?;
+            assert_eq!(res.text().await?, "test");
+        }
+        let json = encode_store_as(&store, Serialization::Json)?;
+        assert!(std::str::from_utf8(&json)?.contains("\"version\": 1"));
+        Ok(())
+    }
+}
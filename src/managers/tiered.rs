@@ -0,0 +1,205 @@
+use crate::{CacheManager, CacheStats};
+
+use anyhow::Result;
+use http_cache_semantics::CachePolicy;
+use reqwest::Response;
+
+/// Implements [`CacheManager`] on top of two other managers, typically a fast in-memory
+/// one (`l1`) in front of a larger persistent one (`l2`).
+/// Responses found only in `l2` are copied into `l1` when read.
+#[derive(Debug, Clone)]
+pub struct TieredManager<L1, L2> {
+    /// Checked first and written on every read from `l2`.
+    pub l1: L1,
+    /// Checked when `l1` has no record.
+    pub l2: L2,
+}
+
+impl<L1, L2> TieredManager<L1, L2> {
+    /// Creates a new manager serving from `l1` first and falling back to `l2`.
+    pub fn new(l1: L1, l2: L2) -> Self {
+        TieredManager { l1, l2 }
+    }
+}
+
+#[async_trait::async_trait]
+impl<L1, L2> CacheManager for TieredManager<L1, L2>
+where
+    L1: CacheManager + Send + Sync,
+    L2: CacheManager + Send + Sync,
+{
+    async fn get(&self, cache_key: &str) -> Result<Option<(Response, CachePolicy)>> {
+        if let Some(cached) = self.l1.get(cache_key).await? {
+            return Ok(Some(cached));
+        }
+        match self.l2.get(cache_key).await? {
+            Some((res, policy)) => {
+                let res = self.l1.put(cache_key, res, policy.clone()).await?;
+                Ok(Some((res, policy)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Policy found in `l1`, or else in `l2`, without promoting it.
+    async fn get_policy(&self, cache_key: &str) -> Result<Option<CachePolicy>> {
+        if let Some(policy) = self.l1.get_policy(cache_key).await? {
+            return Ok(Some(policy));
+        }
+        self.l2.get_policy(cache_key).await
+    }
+
+    async fn put(&self, cache_key: &str, res: Response, policy: CachePolicy) -> Result<Response> {
+        let res = self.l2.put(cache_key, res, policy.clone()).await?;
+        self.l1.put(cache_key, res, policy).await
+    }
+
+    async fn update_metadata(
+        &self,
+        cache_key: &str,
+        res: Response,
+        policy: CachePolicy,
+    ) -> Result<Response> {
+        let res = self
+            .l2
+            .update_metadata(cache_key, res, policy.clone())
+            .await?;
+        self.l1.update_metadata(cache_key, res, policy).await
+    }
+
+    async fn delete(&self, cache_key: &str) -> Result<()> {
+        self.l1.delete(cache_key).await?;
+        self.l2.delete(cache_key).await
+    }
+
+    /// Keys found in either layer, `l2` first.
+    async fn keys(&self) -> Result<Vec<String>> {
+        let mut keys = self.l2.keys().await?;
+        for key in self.l1.keys().await? {
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+
+    async fn invalidate_prefix(&self, prefix: &str) -> Result<()> {
+        self.l1.invalidate_prefix(prefix).await?;
+        self.l2.invalidate_prefix(prefix).await
+    }
+
+    /// Stats of `l2`, which holds every record written, those copied into `l1`
+    /// included.
+    async fn stats(&self) -> Result<CacheStats> {
+        self.l2.stats().await
+    }
+
+    /// Clears both layers, returning how many records were removed from `l2`.
+    /// Fails unless both layers support it.
+    async fn clear_expired(&self) -> Result<usize> {
+        self.l1.clear_expired().await?;
+        self.l2.clear_expired().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::managers::{CacheOp, HashMapManager, RecordingManager};
+    use anyhow::Result;
+    use http::{Method, Response};
+    use reqwest::Request;
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn promotes_l2_hits() -> Result<()> {
+        let url = reqwest::Url::from_str("https://example.com/tiered")?;
+        let res = reqwest::Response::from(Response::new("test"));
+        let key = format!("GET:{}", &url);
+        let req = Request::new(Method::GET, url);
+        let policy = CachePolicy::new(&req, &res);
+        let manager = TieredManager::new(HashMapManager::new(), HashMapManager::new());
+        manager.l2.put(&key, res, policy).await?;
+        assert!(manager.l1.get(&key).await?.is_none());
+        let (res, _) = manager.get(&key).await?.expect("response should be cached");
+        assert_eq!(res.text().await?, "test");
+        let (res, _) = manager
+            .l1
+            .get(&key)
+            .await?
+            .expect("response should be promoted");
+        assert_eq!(res.text().await?, "test");
+        manager.delete(&key).await?;
+        assert!(manager.l1.get(&key).await?.is_none());
+        assert!(manager.l2.get(&key).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn forwards_policy_reads_and_metadata_updates() -> Result<()> {
+        let url = reqwest::Url::from_str("https://example.com/tiered-metadata")?;
+        let res = reqwest::Response::from(Response::new("test"));
+        let key = format!("GET:{}", &url);
+        let req = Request::new(Method::GET, url);
+        let policy = CachePolicy::new(&req, &res);
+        let manager = TieredManager::new(
+            RecordingManager::new(HashMapManager::new()),
+            RecordingManager::new(HashMapManager::new()),
+        );
+        manager.put(&key, res, policy.clone()).await?;
+        assert!(manager.get_policy(&key).await?.is_some());
+        assert!(!manager.l2.ops().contains(&CacheOp::GetPolicy(key.clone())));
+        manager.l1.delete(&key).await?;
+        assert!(manager.get_policy(&key).await?.is_some());
+        assert!(manager.l2.ops().contains(&CacheOp::GetPolicy(key.clone())));
+
+        let (mut res, _) = manager.get(&key).await?.expect("response should be cached");
+        res.headers_mut()
+            .insert("etag", http::HeaderValue::from_static("\"v2\""));
+        let res = manager.update_metadata(&key, res, policy).await?;
+        assert_eq!(res.text().await?, "test");
+        for ops in &[manager.l1.ops(), manager.l2.ops()] {
+            assert!(ops.contains(&CacheOp::UpdateMetadata(key.clone())));
+        }
+        let (res, _) = manager.l1.get(&key).await?.expect("l1 should be updated");
+        assert_eq!(res.headers()["etag"], "\"v2\"");
+        let (res, _) = manager.l2.get(&key).await?.expect("l2 should be updated");
+        assert_eq!(res.headers()["etag"], "\"v2\"");
+        Ok(())
+    }
+
+    #[cfg(feature = "manager-cacache")]
+    #[tokio::test]
+    async fn forwards_stats_and_clearing_expired_records() -> Result<()> {
+        use crate::managers::CACacheManager;
+
+        let manager = TieredManager::new(
+            CACacheManager::new("./reqwest-cacache-tiered-l1"),
+            CACacheManager::new("./reqwest-cacache-tiered-l2"),
+        );
+        let mut keys = Vec::new();
+        for (path, cache_control) in &[("stale", "max-age=0"), ("fresh", "max-age=3600")] {
+            let url = reqwest::Url::from_str(&format!("https://example.com/{}", path))?;
+            let key = format!("GET:{}", &url);
+            let req = Request::new(Method::GET, url);
+            let res = http::Response::builder()
+                .header("cache-control", *cache_control)
+                .body("test")?;
+            let res = reqwest::Response::from(res);
+            let policy = CachePolicy::new(&req, &res);
+            manager.put(&key, res, policy).await?;
+            keys.push(key);
+        }
+        let stats = manager.stats().await?;
+        assert_eq!(stats, manager.l2.stats().await?);
+        assert_eq!(stats.entries, 2);
+        assert_eq!(manager.clear_expired().await?, 1);
+        assert!(manager.l1.get(&keys[0]).await?.is_none());
+        assert!(manager.l2.get(&keys[0]).await?.is_none());
+        assert!(manager.get(&keys[1]).await?.is_some());
+        assert_eq!(manager.stats().await?.entries, 1);
+        manager.l1.clear().await?;
+        manager.l2.clear().await?;
+        Ok(())
+    }
+}
@@ -1,8 +1,21 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
 use anyhow::Result;
-use mockito::mock;
-use reqwest::{Client, Method, Request, Url};
+use mockito::{mock, Matcher};
+use reqwest::{Client, Method};
 use reqwest_middleware::ClientBuilder;
-use reqwest_middleware_cache::{managers::CACacheManager, Cache, CacheManager, CacheMode};
+use reqwest_middleware_cache::{
+    managers::{CacheOp, HashMapManager, RecordingManager},
+    warm, Cache, CacheDecision, CacheError, CacheFreshness, CacheManager, CacheMode,
+    CachePolicyOptions, Clock, HitOrMiss, NotCached, SetCookieMode, X_CACHE_LOOKUP,
+};
+use task_local_extensions::Extensions;
 
 #[tokio::test]
 async fn default_mode() -> Result<()> {
@@ -12,20 +25,17 @@ async fn default_mode() -> Result<()> {
         .with_body("test")
         .create();
     let url = format!("{}/", &mockito::server_url());
-    let manager = CACacheManager::default();
-    let path = manager.path.clone();
+    let manager = HashMapManager::default();
     let key = format!("GET:{}", &url);
-    let req = Request::new(Method::GET, Url::parse(&url)?);
-
-    // Make sure the record doesn't already exist
-    manager.delete(&req).await?;
 
     // Construct reqwest client with cache defaults
     let client = ClientBuilder::new(Client::new())
-        .with(Cache {
-            mode: CacheMode::Default,
-            cache_manager: CACacheManager::default(),
-        })
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager.clone())
+                .build()?,
+        )
         .build();
 
     // Cold pass to load cache
@@ -33,7 +43,2503 @@ async fn default_mode() -> Result<()> {
     m.assert();
 
     // Try to load cached object
-    let data = cacache::read(&path, &key).await;
-    assert!(data.is_ok());
+    assert!(manager.contains_key(&key));
+    Ok(())
+}
+
+#[tokio::test]
+async fn vary_stores_variants_separately() -> Result<()> {
+    let json = mock("GET", "/vary")
+        .match_header("accept", "application/json")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_header("vary", "Accept")
+        .with_body("json")
+        .expect(1)
+        .create();
+    let html = mock("GET", "/vary")
+        .match_header("accept", "text/html")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_header("vary", "Accept")
+        .with_body("html")
+        .expect(1)
+        .create();
+    let url = format!("{}/vary", &mockito::server_url());
+    let manager = HashMapManager::default();
+
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager)
+                .build()?,
+        )
+        .build();
+
+    // Each variant is fetched once, then served from its own record
+    for _ in 0..2 {
+        let res = client
+            .get(&url)
+            .header("accept", "application/json")
+            .send()
+            .await?;
+        assert_eq!(res.text().await?, "json");
+        let res = client
+            .get(&url)
+            .header("accept", "text/html")
+            .send()
+            .await?;
+        assert_eq!(res.text().await?, "html");
+    }
+    json.assert();
+    html.assert();
+    Ok(())
+}
+
+#[cfg(feature = "manager-cacache")]
+#[tokio::test]
+async fn vary_variants_survive_clearing_expired_records() -> Result<()> {
+    use reqwest_middleware_cache::managers::CACacheManager;
+
+    let m = mock("GET", "/vary-expired")
+        .match_header("accept", "application/json")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_header("vary", "Accept")
+        .with_body("json")
+        .expect(1)
+        .create();
+    let url = format!("{}/vary-expired", &mockito::server_url());
+    let manager = CACacheManager::new("./reqwest-cacache-vary-expired");
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager.clone())
+                .build()?,
+        )
+        .build();
+
+    // Cold pass to load cache
+    client
+        .get(&url)
+        .header("accept", "application/json")
+        .send()
+        .await?;
+
+    // The pointer under the primary key is as fresh as the variant behind it
+    assert_eq!(manager.clear_expired().await?, 0);
+    let res = client
+        .get(&url)
+        .header("accept", "application/json")
+        .send()
+        .await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+    assert_eq!(res.text().await?, "json");
+    m.assert();
+    manager.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn revalidated_response_keeps_stored_status() -> Result<()> {
+    let m = mock("GET", "/revalidate")
+        .match_header("if-none-match", Matcher::Missing)
+        .with_status(200)
+        .with_header("cache-control", "no-cache")
+        .with_header("etag", "\"abc\"")
+        .with_body("test")
+        .expect(1)
+        .create();
+    let not_modified = mock("GET", "/revalidate")
+        .match_header("if-none-match", "\"abc\"")
+        .with_status(304)
+        .with_header("etag", "\"abc\"")
+        .expect(1)
+        .create();
+    let url = format!("{}/revalidate", &mockito::server_url());
+    let manager = HashMapManager::default();
+
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager)
+                .build()?,
+        )
+        .build();
+
+    // Cold pass to load cache
+    client.get(&url).send().await?;
+    m.assert();
+
+    // Stale record gets revalidated and is served as the original 200
+    let res = client.get(&url).send().await?;
+    not_modified.assert();
+    assert_eq!(res.status(), 200);
+    assert_eq!(
+        res.extensions().get::<HitOrMiss>(),
+        Some(&HitOrMiss::Revalidated)
+    );
+    assert_eq!(res.text().await?, "test");
+    Ok(())
+}
+
+#[tokio::test]
+async fn mode_can_be_overridden_per_request() -> Result<()> {
+    let m = mock("GET", "/override")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("test")
+        .expect(2)
+        .create();
+    let url = format!("{}/override", &mockito::server_url());
+    let manager = HashMapManager::default();
+
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager)
+                .build()?,
+        )
+        .build();
+
+    // Cold pass to load cache
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Miss));
+
+    // Fresh record is served from cache
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+
+    // Reload goes to the network despite the fresh record
+    let mut extensions = Extensions::new();
+    extensions.insert(CacheMode::Reload);
+    client
+        .get(&url)
+        .send_with_extensions(&mut extensions)
+        .await?;
+    m.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn caches_not_found_with_explicit_freshness() -> Result<()> {
+    let m = mock("GET", "/missing")
+        .with_status(404)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("not found")
+        .expect(1)
+        .create();
+    let url = format!("{}/missing", &mockito::server_url());
+    let manager = HashMapManager::default();
+
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager)
+                .build()?,
+        )
+        .build();
+
+    client.get(&url).send().await?;
+    let res = client.get(&url).send().await?;
+    m.assert();
+    assert_eq!(res.status(), 404);
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+    Ok(())
+}
+
+#[tokio::test]
+async fn caches_permanent_redirect() -> Result<()> {
+    let m = mock("GET", "/moved")
+        .with_status(301)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_header("location", "/elsewhere")
+        .expect(1)
+        .create();
+    let url = format!("{}/moved", &mockito::server_url());
+    let manager = HashMapManager::default();
+
+    // Redirects are left for the caller so the middleware sees the 301
+    let client = Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()?;
+    let client = ClientBuilder::new(client)
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager)
+                .build()?,
+        )
+        .build();
+
+    client.get(&url).send().await?;
+    let res = client.get(&url).send().await?;
+    m.assert();
+    assert_eq!(res.status(), 301);
+    assert_eq!(res.headers()["location"], "/elsewhere");
+    Ok(())
+}
+
+#[test]
+fn builder_requires_cache_manager() {
+    assert!(Cache::<HashMapManager>::builder().build().is_err());
+    assert!(Cache::builder()
+        .cache_manager(HashMapManager::default())
+        .build()
+        .is_ok());
+}
+
+#[tokio::test]
+async fn caches_configured_methods() -> Result<()> {
+    let m = mock("POST", "/query")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("test")
+        .expect(1)
+        .create();
+    let url = format!("{}/query", &mockito::server_url());
+    let manager = HashMapManager::default();
+
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager)
+                .cacheable_methods(vec![Method::GET, Method::HEAD, Method::POST])
+                .build()?,
+        )
+        .build();
+
+    client.post(&url).send().await?;
+    let res = client.post(&url).send().await?;
+    m.assert();
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+    Ok(())
+}
+
+#[tokio::test]
+async fn custom_cache_key() -> Result<()> {
+    let m = mock("GET", "/tenant")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("test")
+        .expect(2)
+        .create();
+    let url = format!("{}/tenant", &mockito::server_url());
+    let manager = HashMapManager::default();
+
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager)
+                .cache_key(|req| {
+                    let tenant = req
+                        .headers()
+                        .get("x-tenant-id")
+                        .and_then(|val| val.to_str().ok())
+                        .unwrap_or_default();
+                    format!("{}:{}:{}", tenant, req.method(), req.url())
+                })
+                .build()?,
+        )
+        .build();
+
+    // Each tenant gets its own record
+    for _ in 0..2 {
+        for tenant in &["a", "b"] {
+            client
+                .get(&url)
+                .header("x-tenant-id", *tenant)
+                .send()
+                .await?;
+        }
+    }
+    m.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn stale_while_revalidate_updates_in_background() -> Result<()> {
+    let m = mock("GET", "/swr")
+        .match_header("if-none-match", Matcher::Missing)
+        .with_status(200)
+        .with_header("cache-control", "max-age=0, stale-while-revalidate=60")
+        .with_header("etag", "\"v1\"")
+        .with_body("old")
+        .expect(1)
+        .create();
+    let updated = mock("GET", "/swr")
+        .match_header("if-none-match", "\"v1\"")
+        .with_status(200)
+        .with_header("cache-control", "max-age=3600")
+        .with_header("etag", "\"v2\"")
+        .with_body("new")
+        .expect(1)
+        .create();
+    let url = format!("{}/swr", &mockito::server_url());
+    let key = format!("GET:{}", &url);
+    let manager = HashMapManager::default();
+
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager.clone())
+                .revalidation_client(Client::new())
+                .build()?,
+        )
+        .build();
+
+    // Cold pass to load cache
+    client.get(&url).send().await?;
+    m.assert();
+
+    // Stale record is served right away
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+    assert!(res.headers()["warning"].to_str()?.starts_with("110 "));
+    assert_eq!(res.text().await?, "old");
+
+    // Meanwhile the record gets refreshed
+    let mut body = String::new();
+    for _ in 0..50 {
+        if let Some((res, _)) = manager.get(&key).await? {
+            body = res.text().await?;
+            if body == "new" {
+                break;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    updated.assert();
+    assert_eq!(body, "new");
+    Ok(())
+}
+
+#[tokio::test]
+async fn stale_if_error_bounds_stale_responses() -> Result<()> {
+    let manager = HashMapManager::default();
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager)
+                .build()?,
+        )
+        .build();
+
+    for (path, window, served_stale) in [("/sie-within", 60, true), ("/sie-expired", 0, false)] {
+        let m = mock("GET", path)
+            .match_header("if-none-match", Matcher::Missing)
+            .with_status(200)
+            .with_header(
+                "cache-control",
+                &format!("max-age=0, stale-if-error={}", window),
+            )
+            .with_header("etag", "\"abc\"")
+            .with_body("test")
+            .expect(1)
+            .create();
+        let failing = mock("GET", path)
+            .match_header("if-none-match", "\"abc\"")
+            .with_status(503)
+            .expect(1)
+            .create();
+        let url = format!("{}{}", &mockito::server_url(), path);
+
+        // Cold pass to load cache
+        client.get(&url).send().await?;
+        m.assert();
+
+        let res = client.get(&url).send().await?;
+        failing.assert();
+        if served_stale {
+            assert_eq!(res.status(), 200);
+            assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+            assert_eq!(res.text().await?, "test");
+        } else {
+            assert_eq!(res.status(), 503);
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn hooks_report_hits_and_misses() -> Result<()> {
+    let m = mock("GET", "/hooks")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("test")
+        .expect(1)
+        .create();
+    let url = format!("{}/hooks", &mockito::server_url());
+    let manager = HashMapManager::default();
+
+    let hits = Arc::new(AtomicUsize::new(0));
+    let misses = Arc::new(AtomicUsize::new(0));
+    let hit_counter = hits.clone();
+    let miss_counter = misses.clone();
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager)
+                .on_hit(move |_| {
+                    hit_counter.fetch_add(1, Ordering::SeqCst);
+                })
+                .on_miss(move |_| {
+                    miss_counter.fetch_add(1, Ordering::SeqCst);
+                })
+                .build()?,
+        )
+        .build();
+
+    client.get(&url).send().await?;
+    client.get(&url).send().await?;
+    client.get(&url).send().await?;
+    m.assert();
+    assert_eq!(misses.load(Ordering::SeqCst), 1);
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn default_ttl_caches_responses_without_headers() -> Result<()> {
+    let m = mock("GET", "/no-headers")
+        .with_status(200)
+        .with_body("test")
+        .expect(1)
+        .create();
+    let url = format!("{}/no-headers", &mockito::server_url());
+    let manager = HashMapManager::default();
+
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager)
+                .default_ttl(Duration::from_secs(60))
+                .build()?,
+        )
+        .build();
+
+    // Cold pass to load cache
+    client.get(&url).send().await?;
+
+    // Served from cache for the default lifetime
+    let res = client.get(&url).send().await?;
+    m.assert();
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+    assert!(res.headers().get("cache-control").is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn request_no_store_bypasses_cache() -> Result<()> {
+    let m = mock("GET", "/request-no-store")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("test")
+        .expect(2)
+        .create();
+    let url = format!("{}/request-no-store", &mockito::server_url());
+    let manager = HashMapManager::default();
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager.clone())
+                .build()?,
+        )
+        .build();
+
+    for _ in 0..2 {
+        let res = client
+            .get(&url)
+            .header("cache-control", "no-store")
+            .send()
+            .await?;
+        assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Miss));
+    }
+    m.assert();
+    assert!(manager.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn stale_while_revalidate_needs_a_revalidation_client() -> Result<()> {
+    let m = mock("GET", "/swr-foreground")
+        .match_header("if-none-match", Matcher::Missing)
+        .with_status(200)
+        .with_header("cache-control", "max-age=0, stale-while-revalidate=60")
+        .with_header("etag", "\"v1\"")
+        .with_body("old")
+        .expect(1)
+        .create();
+    let updated = mock("GET", "/swr-foreground")
+        .match_header("if-none-match", "\"v1\"")
+        .with_status(200)
+        .with_header("cache-control", "max-age=3600")
+        .with_header("etag", "\"v2\"")
+        .with_body("new")
+        .expect(1)
+        .create();
+    let url = format!("{}/swr-foreground", &mockito::server_url());
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(HashMapManager::default())
+                .build()?,
+        )
+        .build();
+
+    // Cold pass to load cache
+    client.get(&url).send().await?;
+    m.assert();
+
+    // Without a client to revalidate in the background, the stale record is
+    // revalidated through the middleware stack before being served
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Miss));
+    assert_eq!(res.text().await?, "new");
+    updated.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn request_freshness_directives_force_revalidation() -> Result<()> {
+    let m = mock("GET", "/request-max-age")
+        .match_header("if-none-match", Matcher::Missing)
+        .with_status(200)
+        .with_header("cache-control", "max-age=3600, stale-while-revalidate=60")
+        .with_header("etag", "\"abc\"")
+        .with_body("test")
+        .expect(1)
+        .create();
+    let not_modified = mock("GET", "/request-max-age")
+        .match_header("if-none-match", "\"abc\"")
+        .with_status(304)
+        .with_header("cache-control", "max-age=3600, stale-while-revalidate=60")
+        .with_header("etag", "\"abc\"")
+        .expect(2)
+        .create();
+    let url = format!("{}/request-max-age", &mockito::server_url());
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(HashMapManager::default())
+                .build()?,
+        )
+        .build();
+
+    // Cold pass to load cache
+    client.get(&url).send().await?;
+    m.assert();
+
+    // Fresh enough for a plain request
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+
+    // Not for requests asking for more
+    for directive in &["max-age=0", "min-fresh=7200"] {
+        let res = client
+            .get(&url)
+            .header("cache-control", *directive)
+            .send()
+            .await?;
+        assert_eq!(
+            res.extensions().get::<HitOrMiss>(),
+            Some(&HitOrMiss::Revalidated)
+        );
+        assert_eq!(res.text().await?, "test");
+    }
+    not_modified.assert();
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+struct ManualClock(Arc<Mutex<SystemTime>>);
+
+impl ManualClock {
+    fn advance(&self, by: Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.0.lock().unwrap()
+    }
+}
+
+#[tokio::test]
+async fn clock_drives_freshness() -> Result<()> {
+    let m = mock("GET", "/clock")
+        .match_header("if-none-match", Matcher::Missing)
+        .with_status(200)
+        .with_header("cache-control", "max-age=60")
+        .with_header("etag", "\"abc\"")
+        .with_body("test")
+        .expect(1)
+        .create();
+    let not_modified = mock("GET", "/clock")
+        .match_header("if-none-match", "\"abc\"")
+        .with_status(304)
+        .with_header("cache-control", "max-age=60")
+        .with_header("etag", "\"abc\"")
+        .expect(1)
+        .create();
+    let url = format!("{}/clock", &mockito::server_url());
+    let clock = ManualClock(Arc::new(Mutex::new(SystemTime::now())));
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(HashMapManager::default())
+                .clock(clock.clone())
+                .build()?,
+        )
+        .build();
+
+    // Cold pass to load cache
+    client.get(&url).send().await?;
+    m.assert();
+
+    // Fresh until the clock moves past max-age
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+    clock.advance(Duration::from_secs(120));
+    let res = client.get(&url).send().await?;
+    assert_eq!(
+        res.extensions().get::<HitOrMiss>(),
+        Some(&HitOrMiss::Revalidated)
+    );
+    assert_eq!(res.text().await?, "test");
+    not_modified.assert();
+
+    // The revalidated response is fresh again
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+    Ok(())
+}
+
+#[tokio::test]
+async fn bypass_header_skips_cache() -> Result<()> {
+    let m = mock("GET", "/bypass")
+        .match_header("x-cache-bypass", Matcher::Missing)
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("test")
+        .expect(2)
+        .create();
+    let url = format!("{}/bypass", &mockito::server_url());
+    let manager = HashMapManager::default();
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager.clone())
+                .bypass_header(reqwest::header::HeaderName::from_static("x-cache-bypass"))
+                .build()?,
+        )
+        .build();
+
+    for _ in 0..2 {
+        let res = client
+            .get(&url)
+            .header("x-cache-bypass", "1")
+            .send()
+            .await?;
+        assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Miss));
+    }
+    m.assert();
+    assert!(manager.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn only_if_cached_miss_is_distinguishable() -> Result<()> {
+    let m = mock("GET", "/only-if-cached").expect(0).create();
+    let url = format!("{}/only-if-cached", &mockito::server_url());
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::OnlyIfCached)
+                .cache_manager(HashMapManager::default())
+                .build()?,
+        )
+        .build();
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.status(), 504);
+    assert_eq!(res.headers()[X_CACHE_LOOKUP], "MISS");
+
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::OnlyIfCached)
+                .cache_manager(HashMapManager::default())
+                .not_cached_error(true)
+                .build()?,
+        )
+        .build();
+    match client.get(&url).send().await {
+        Err(reqwest_middleware::Error::Middleware(e)) => assert!(e.is::<NotCached>()),
+        other => panic!("expected a NotCached error, got {:?}", other),
+    }
+    m.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn revalidation_keeps_binary_bodies() -> Result<()> {
+    let body: &[u8] = &[0xff, 0xfe, 0x00, 0x80];
+    let m = mock("GET", "/binary")
+        .match_header("if-none-match", Matcher::Missing)
+        .with_status(200)
+        .with_header("cache-control", "no-cache")
+        .with_header("etag", "\"abc\"")
+        .with_body(body)
+        .expect(1)
+        .create();
+    let not_modified = mock("GET", "/binary")
+        .match_header("if-none-match", "\"abc\"")
+        .with_status(304)
+        .with_header("etag", "\"abc\"")
+        .expect(2)
+        .create();
+    let url = format!("{}/binary", &mockito::server_url());
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(HashMapManager::default())
+                .build()?,
+        )
+        .build();
+
+    // Cold pass to load cache
+    client.get(&url).send().await?;
+    m.assert();
+
+    // Every revalidation serves the stored bytes untouched
+    for _ in 0..2 {
+        let res = client.get(&url).send().await?;
+        assert_eq!(
+            res.extensions().get::<HitOrMiss>(),
+            Some(&HitOrMiss::Revalidated)
+        );
+        assert_eq!(&res.bytes().await?[..], body);
+    }
+    not_modified.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn head_response_freshens_stored_get() -> Result<()> {
+    let m = mock("GET", "/head")
+        .with_status(200)
+        .with_header("cache-control", "max-age=0")
+        .with_header("etag", "\"abc\"")
+        .with_body("test")
+        .expect(1)
+        .create();
+    let head = mock("HEAD", "/head")
+        .with_status(200)
+        .with_header("cache-control", "max-age=3600")
+        .with_header("etag", "\"abc\"")
+        .expect(1)
+        .create();
+    let url = format!("{}/head", &mockito::server_url());
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(HashMapManager::default())
+                .build()?,
+        )
+        .build();
+
+    // Cold pass to load cache
+    client.get(&url).send().await?;
+    m.assert();
+
+    // The HEAD response carries the same validator, so the stored GET is freshened
+    client.head(&url).send().await?;
+    head.assert();
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+    assert_eq!(res.headers()["cache-control"], "max-age=3600");
+    assert_eq!(res.text().await?, "test");
+    Ok(())
+}
+
+#[tokio::test]
+async fn boxed_manager_caches_responses() -> Result<()> {
+    let m = mock("GET", "/boxed")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("test")
+        .expect(1)
+        .create();
+    let url = format!("{}/boxed", &mockito::server_url());
+    let manager: Box<dyn CacheManager + Send + Sync> = Box::new(HashMapManager::default());
+    let client = ClientBuilder::new(Client::new())
+        .with(Cache::boxed(CacheMode::Default, manager))
+        .build();
+
+    // Cold pass to load cache
+    client.get(&url).send().await?;
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+    assert_eq!(res.text().await?, "test");
+    m.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn cached_responses_carry_age() -> Result<()> {
+    let m = mock("GET", "/age")
+        .with_status(200)
+        .with_header("cache-control", "max-age=3600")
+        .with_header("age", "10")
+        .with_body("test")
+        .expect(1)
+        .create();
+    let url = format!("{}/age", &mockito::server_url());
+    let clock = ManualClock(Arc::new(Mutex::new(SystemTime::now())));
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(HashMapManager::default())
+                .clock(clock.clone())
+                .build()?,
+        )
+        .build();
+
+    // Cold pass to load cache
+    client.get(&url).send().await?;
+    m.assert();
+
+    clock.advance(Duration::from_secs(30));
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+    assert_eq!(res.headers()["age"], "40");
+    let freshness = res
+        .extensions()
+        .get::<CacheFreshness>()
+        .expect("cached responses carry their freshness");
+    assert_eq!(freshness.age, Duration::from_secs(40));
+    assert_eq!(freshness.time_to_live, Duration::from_secs(3560));
+    assert!(!freshness.stale);
+
+    // Also when staleness isn't checked
+    let mut extensions = Extensions::new();
+    extensions.insert(CacheMode::ForceCache);
+    let res = client
+        .get(&url)
+        .send_with_extensions(&mut extensions)
+        .await?;
+    assert_eq!(res.headers()["age"], "40");
+    Ok(())
+}
+
+#[tokio::test]
+async fn unsafe_requests_invalidate_stored_responses() -> Result<()> {
+    let m = mock("GET", "/unsafe")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("test")
+        .expect(2)
+        .create();
+    let post = mock("POST", "/unsafe").with_status(201).expect(1).create();
+    let url = format!("{}/unsafe", &mockito::server_url());
+    let manager = HashMapManager::default();
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager.clone())
+                .build()?,
+        )
+        .build();
+
+    // Cold pass to load cache
+    client.get(&url).send().await?;
+    assert!(manager.contains_key(&format!("GET:{}", &url)));
+
+    client.post(&url).send().await?;
+    post.assert();
+    assert!(!manager.contains_key(&format!("GET:{}", &url)));
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Miss));
+    m.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn unsafe_requests_invalidate_locations() -> Result<()> {
+    let m = mock("GET", "/users/1")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("test")
+        .expect(2)
+        .create();
+    let elsewhere = mock("GET", "/teams/1")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("test")
+        .expect(1)
+        .create();
+    let post = mock("POST", "/users")
+        .with_status(201)
+        .with_header("location", "/users/1")
+        .with_header("content-location", "https://example.com/teams/1")
+        .expect(1)
+        .create();
+    let user_url = format!("{}/users/1", &mockito::server_url());
+    let team_url = format!("{}/teams/1", &mockito::server_url());
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(HashMapManager::default())
+                .build()?,
+        )
+        .build();
+
+    // Cold pass to load cache
+    client.get(&user_url).send().await?;
+    client.get(&team_url).send().await?;
+
+    client
+        .post(&format!("{}/users", &mockito::server_url()))
+        .send()
+        .await?;
+    post.assert();
+
+    // Only the same-origin location is invalidated
+    let res = client.get(&user_url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Miss));
+    let res = client.get(&team_url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+    m.assert();
+    elsewhere.assert();
+    Ok(())
+}
+
+// Streaming bodies need reqwest's `stream` feature, enabled along with cacache.
+#[cfg(feature = "manager-cacache")]
+#[tokio::test]
+async fn streaming_request_bodies_pass_through() -> Result<()> {
+    let m = mock("POST", "/stream")
+        .match_body("test")
+        .with_status(200)
+        .expect(1)
+        .create();
+    let url = format!("{}/stream", &mockito::server_url());
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(HashMapManager::default())
+                .build()?,
+        )
+        .build();
+    let chunks: Vec<Result<&'static str, std::io::Error>> = vec![Ok("te"), Ok("st")];
+    let body = reqwest::Body::wrap_stream(futures::stream::iter(chunks));
+    let res = client.post(&url).body(body).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Miss));
+    m.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn large_bodies_are_not_stored() -> Result<()> {
+    let m = mock("GET", "/large")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("a body longer than the limit")
+        .expect(2)
+        .create();
+    let url = format!("{}/large", &mockito::server_url());
+    let manager = HashMapManager::default();
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager.clone())
+                .max_body_bytes(8)
+                .build()?,
+        )
+        .build();
+
+    for _ in 0..2 {
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Miss));
+        assert_eq!(res.text().await?, "a body longer than the limit");
+    }
+    m.assert();
+    assert!(manager.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn single_flight_coalesces_concurrent_misses() -> Result<()> {
+    let m = mock("GET", "/single-flight")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("test")
+        .expect(1)
+        .create();
+    let url = format!("{}/single-flight", &mockito::server_url());
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(HashMapManager::default())
+                .single_flight(true)
+                .build()?,
+        )
+        .build();
+
+    let requests = (0..5).map(|_| {
+        let client = client.clone();
+        let url = url.clone();
+        tokio::spawn(async move { client.get(&url).send().await })
+    });
+    let mut misses = 0;
+    for request in requests.collect::<Vec<_>>() {
+        let res = request.await??;
+        if res.extensions().get::<HitOrMiss>() == Some(&HitOrMiss::Miss) {
+            misses += 1;
+        }
+        assert_eq!(res.text().await?, "test");
+    }
+    assert_eq!(misses, 1);
+    m.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn private_responses_only_stored_by_private_caches() -> Result<()> {
+    let m = mock("GET", "/private")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, private")
+        .with_body("test")
+        .expect(3)
+        .create();
+    let url = format!("{}/private", &mockito::server_url());
+    for shared in &[true, false] {
+        let client = ClientBuilder::new(Client::new())
+            .with(
+                Cache::builder()
+                    .mode(CacheMode::Default)
+                    .cache_manager(HashMapManager::default())
+                    .shared(*shared)
+                    .build()?,
+            )
+            .build();
+        client.get(&url).send().await?;
+        let res = client.get(&url).send().await?;
+        let expected = if *shared {
+            HitOrMiss::Miss
+        } else {
+            HitOrMiss::Hit
+        };
+        assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&expected));
+    }
+    m.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn read_only_cache_never_writes() -> Result<()> {
+    let m = mock("GET", "/read-only")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("test")
+        .expect(3)
+        .create();
+    let url = format!("{}/read-only", &mockito::server_url());
+    let manager = HashMapManager::default();
+    let reader = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager.clone())
+                .read_only(true)
+                .build()?,
+        )
+        .build();
+
+    // Misses aren't stored
+    for _ in 0..2 {
+        let res = reader.get(&url).send().await?;
+        assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Miss));
+    }
+    assert!(manager.is_empty());
+
+    // Responses stored by a writer are served
+    let writer = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager.clone())
+                .build()?,
+        )
+        .build();
+    writer.get(&url).send().await?;
+    let res = reader.get(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+    m.assert();
+    Ok(())
+}
+
+#[derive(Clone)]
+struct UnavailableManager;
+
+#[async_trait::async_trait]
+impl CacheManager for UnavailableManager {
+    async fn get(
+        &self,
+        _: &str,
+    ) -> Result<Option<(reqwest::Response, http_cache_semantics::CachePolicy)>> {
+        Err(anyhow::anyhow!("backend unavailable"))
+    }
+
+    async fn put(
+        &self,
+        _: &str,
+        _: reqwest::Response,
+        _: http_cache_semantics::CachePolicy,
+    ) -> Result<reqwest::Response> {
+        Err(anyhow::anyhow!("backend unavailable"))
+    }
+
+    async fn delete(&self, _: &str) -> Result<()> {
+        Err(anyhow::anyhow!("backend unavailable"))
+    }
+}
+
+#[tokio::test]
+async fn fail_open_ignores_backend_errors() -> Result<()> {
+    let m = mock("GET", "/fail-open")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("test")
+        .expect(2)
+        .create();
+    let url = format!("{}/fail-open", &mockito::server_url());
+
+    // Fail closed by default
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(UnavailableManager)
+                .build()?,
+        )
+        .build();
+    match client.get(&url).send().await {
+        Err(reqwest_middleware::Error::Middleware(e)) => {
+            assert!(matches!(
+                e.downcast_ref::<CacheError>(),
+                Some(CacheError::Backend(_))
+            ));
+        }
+        res => panic!("expected a backend error, got {:?}", res),
+    }
+
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(UnavailableManager)
+                .fail_open(true)
+                .build()?,
+        )
+        .build();
+    for _ in 0..2 {
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Miss));
+        assert_eq!(res.text().await?, "test");
+    }
+    // Unsafe requests invalidating the URL don't fail either
+    let _post = mock("POST", "/fail-open").with_status(201).create();
+    assert_eq!(client.post(&url).send().await?.status(), 201);
+    m.assert();
+    Ok(())
+}
+
+#[derive(Clone, Default)]
+struct FailingUpdateManager(HashMapManager);
+
+#[async_trait::async_trait]
+impl CacheManager for FailingUpdateManager {
+    async fn get(
+        &self,
+        cache_key: &str,
+    ) -> Result<Option<(reqwest::Response, http_cache_semantics::CachePolicy)>> {
+        self.0.get(cache_key).await
+    }
+
+    async fn put(
+        &self,
+        cache_key: &str,
+        res: reqwest::Response,
+        policy: http_cache_semantics::CachePolicy,
+    ) -> Result<reqwest::Response> {
+        self.0.put(cache_key, res, policy).await
+    }
+
+    async fn update_metadata(
+        &self,
+        _: &str,
+        _: reqwest::Response,
+        _: http_cache_semantics::CachePolicy,
+    ) -> Result<reqwest::Response> {
+        Err(anyhow::anyhow!("backend unavailable"))
+    }
+
+    async fn delete(&self, cache_key: &str) -> Result<()> {
+        self.0.delete(cache_key).await
+    }
+}
+
+#[tokio::test]
+async fn fail_open_serves_revalidated_responses_when_updates_fail() -> Result<()> {
+    let m = mock("GET", "/fail-open-update")
+        .match_header("if-none-match", Matcher::Missing)
+        .with_status(200)
+        .with_header("cache-control", "max-age=0")
+        .with_header("etag", "\"v1\"")
+        .with_body("test")
+        .expect(1)
+        .create();
+    let not_modified = mock("GET", "/fail-open-update")
+        .match_header("if-none-match", "\"v1\"")
+        .with_status(304)
+        .with_header("cache-control", "max-age=0")
+        .with_header("etag", "\"v1\"")
+        .with_header("x-revalidated", "yes")
+        .expect(1)
+        .create();
+    let url = format!("{}/fail-open-update", &mockito::server_url());
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(FailingUpdateManager::default())
+                .fail_open(true)
+                .build()?,
+        )
+        .build();
+
+    // Cold pass to load cache
+    client.get(&url).send().await?;
+
+    // The stored body is served under the headers of the 304
+    let res = client.get(&url).send().await?;
+    assert_eq!(
+        res.extensions().get::<HitOrMiss>(),
+        Some(&HitOrMiss::Revalidated)
+    );
+    assert_eq!(res.headers()["x-revalidated"], "yes");
+    assert_eq!(res.text().await?, "test");
+    m.assert();
+    not_modified.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn revalidation_keeps_repeated_headers() -> Result<()> {
+    let m = mock("GET", "/repeated-headers")
+        .match_header("if-none-match", Matcher::Missing)
+        .with_status(200)
+        .with_header("cache-control", "no-cache")
+        .with_header("etag", "\"v1\"")
+        .with_header("set-cookie", "a=1")
+        .with_header("set-cookie", "b=2")
+        .with_body("test")
+        .expect(1)
+        .create();
+    let not_modified = mock("GET", "/repeated-headers")
+        .match_header("if-none-match", "\"v1\"")
+        .with_status(304)
+        .with_header("cache-control", "no-cache")
+        .with_header("etag", "\"v1\"")
+        .expect(2)
+        .create();
+    let url = format!("{}/repeated-headers", &mockito::server_url());
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(HashMapManager::default())
+                .shared(false)
+                .build()?,
+        )
+        .build();
+
+    // Cold pass to load cache
+    client.get(&url).send().await?;
+
+    // Both cookies survive the 304, and the headers written back after it
+    for _ in 0..2 {
+        let res = client.get(&url).send().await?;
+        assert_eq!(
+            res.extensions().get::<HitOrMiss>(),
+            Some(&HitOrMiss::Revalidated)
+        );
+        let cookies: Vec<_> = res.headers().get_all("set-cookie").iter().collect();
+        assert_eq!(cookies, vec!["a=1", "b=2"]);
+    }
+    m.assert();
+    not_modified.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn immutable_responses_are_not_revalidated_while_fresh() -> Result<()> {
+    let m = mock("GET", "/immutable")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public, immutable")
+        .with_header("etag", "\"v1\"")
+        .with_body("test")
+        .expect(1)
+        .create();
+    let url = format!("{}/immutable", &mockito::server_url());
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::NoCache)
+                .cache_manager(HashMapManager::default())
+                .build()?,
+        )
+        .build();
+
+    // Cold pass to load cache
+    client.get(&url).send().await?;
+
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+    let res = client
+        .get(&url)
+        .header("cache-control", "max-age=0")
+        .send()
+        .await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+    assert_eq!(res.text().await?, "test");
+    m.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn refresh_ahead_refreshes_fresh_responses() -> Result<()> {
+    let m = mock("GET", "/refresh-ahead")
+        .match_header("if-none-match", Matcher::Missing)
+        .with_status(200)
+        .with_header("cache-control", "max-age=100")
+        .with_header("etag", "\"abc\"")
+        .with_body("test")
+        .expect(1)
+        .create();
+    let not_modified = mock("GET", "/refresh-ahead")
+        .match_header("if-none-match", "\"abc\"")
+        .with_status(304)
+        .with_header("cache-control", "max-age=100")
+        .with_header("etag", "\"abc\"")
+        .expect(1)
+        .create();
+    let url = format!("{}/refresh-ahead", &mockito::server_url());
+    let clock = ManualClock(Arc::new(Mutex::new(SystemTime::now())));
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(HashMapManager::default())
+                .clock(clock.clone())
+                .refresh_ahead(0.8)
+                .revalidation_client(Client::new())
+                .build()?,
+        )
+        .build();
+
+    // Cold pass to load cache
+    client.get(&url).send().await?;
+
+    // Not refreshed before the threshold
+    clock.advance(Duration::from_secs(50));
+    client.get(&url).send().await?;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!not_modified.matched());
+
+    // Served from the cache and refreshed past it
+    clock.advance(Duration::from_secs(40));
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+    assert!(res.headers().get("warning").is_none());
+    assert_eq!(res.text().await?, "test");
+    for _ in 0..50 {
+        if not_modified.matched() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    not_modified.assert();
+    m.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn warnings_can_be_disabled() -> Result<()> {
+    let m = mock("GET", "/no-warnings")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("test")
+        .expect(1)
+        .create();
+    let url = format!("{}/no-warnings", &mockito::server_url());
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::ForceCache)
+                .cache_manager(HashMapManager::default())
+                .warnings(false)
+                .build()?,
+        )
+        .build();
+
+    // Cold pass to load cache
+    client.get(&url).send().await?;
+
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+    assert!(res.headers().get("warning").is_none());
+    m.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn reload_overwrites_stale_responses() -> Result<()> {
+    let url = format!("{}/reload", &mockito::server_url());
+    let key = format!("GET:{}", &url);
+    let manager = HashMapManager::default();
+    let clock = ManualClock(Arc::new(Mutex::new(SystemTime::now())));
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager.clone())
+                .clock(clock.clone())
+                .build()?,
+        )
+        .build();
+
+    // Cold pass to load cache
+    let old = mock("GET", "/reload")
+        .with_status(200)
+        .with_header("cache-control", "max-age=60")
+        .with_body("old")
+        .expect(1)
+        .create();
+    client.get(&url).send().await?;
+    old.assert();
+    drop(old);
+
+    // Reload skips the stale record and writes the new response over it
+    clock.advance(Duration::from_secs(120));
+    let new = mock("GET", "/reload")
+        .with_status(200)
+        .with_header("cache-control", "max-age=60")
+        .with_body("new")
+        .expect(1)
+        .create();
+    let mut extensions = Extensions::new();
+    extensions.insert(CacheMode::Reload);
+    let res = client
+        .get(&url)
+        .send_with_extensions(&mut extensions)
+        .await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Miss));
+    assert_eq!(res.text().await?, "new");
+    let (stored, _) = manager.get(&key).await?.expect("response should be cached");
+    assert_eq!(stored.text().await?, "new");
+
+    // And is served fresh from then on
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+    assert_eq!(res.text().await?, "new");
+    new.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn warm_stores_responses_ahead_of_time() -> Result<()> {
+    let m = mock("GET", "/warm")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("test")
+        .expect(1)
+        .create();
+    let url = format!("{}/warm", &mockito::server_url());
+    let manager = HashMapManager::default();
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager.clone())
+                .build()?,
+        )
+        .build();
+
+    let results = warm(&client, vec![url.clone(), "not a url".to_owned()]).await;
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].0, url);
+    assert_eq!(*results[0].1.as_ref().unwrap(), 200);
+    assert!(results[1].1.is_err());
+
+    // Served from the cache from the first request on
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+    m.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn expires_without_cache_control_sets_freshness() -> Result<()> {
+    let in_an_hour = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(3600));
+    let fresh = mock("GET", "/expires-future")
+        .with_status(200)
+        .with_header("expires", &in_an_hour)
+        .with_body("test")
+        .expect(1)
+        .create();
+    let an_hour_ago = httpdate::fmt_http_date(SystemTime::now() - Duration::from_secs(3600));
+    let expired = mock("GET", "/expires-past")
+        .with_status(200)
+        .with_header("expires", &an_hour_ago)
+        .with_body("test")
+        .expect(2)
+        .create();
+    let manager = HashMapManager::default();
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager.clone())
+                .build()?,
+        )
+        .build();
+
+    // Served from the cache until it expires
+    let url = format!("{}/expires-future", &mockito::server_url());
+    client.get(&url).send().await?;
+    assert!(manager.contains_key(&format!("GET:{}", &url)));
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+    assert_eq!(res.text().await?, "test");
+    fresh.assert();
+
+    // And fetched again once it has
+    let url = format!("{}/expires-past", &mockito::server_url());
+    client.get(&url).send().await?;
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Miss));
+    assert_eq!(res.text().await?, "test");
+    expired.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn warning_dates_come_from_the_clock() -> Result<()> {
+    let m = mock("GET", "/warn-date")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("test")
+        .expect(1)
+        .create();
+    let url = format!("{}/warn-date", &mockito::server_url());
+    let clock = ManualClock(Arc::new(Mutex::new(
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000),
+    )));
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::ForceCache)
+                .cache_manager(HashMapManager::default())
+                .clock(clock)
+                .warn_agent("edge-cache")
+                .build()?,
+        )
+        .build();
+
+    // Cold pass to load cache
+    client.get(&url).send().await?;
+
+    let res = client.get(&url).send().await?;
+    assert_eq!(
+        res.headers()["warning"],
+        "112 edge-cache \"Disconnected operation\" \"Sun, 09 Sep 2001 01:46:40 GMT\""
+    );
+    m.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn set_cookie_responses_can_be_kept_out_of_shared_caches() -> Result<()> {
+    let stripped = mock("GET", "/set-cookie-strip")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_header("set-cookie", "session=abc")
+        .with_body("test")
+        .expect(1)
+        .create();
+    let refused = mock("GET", "/set-cookie-refuse")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_header("set-cookie", "session=abc")
+        .with_body("test")
+        .expect(2)
+        .create();
+    let cache = |mode| -> Result<_> {
+        let manager = HashMapManager::default();
+        let client = ClientBuilder::new(Client::new())
+            .with(
+                Cache::builder()
+                    .mode(CacheMode::Default)
+                    .cache_manager(manager.clone())
+                    .set_cookie(mode)
+                    .build()?,
+            )
+            .build();
+        Ok((client, manager))
+    };
+
+    // Stored without the cookie, which only the first user gets
+    let (client, manager) = cache(SetCookieMode::Strip)?;
+    let url = format!("{}/set-cookie-strip", &mockito::server_url());
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.headers()["set-cookie"], "session=abc");
+    let (stored, _) = manager
+        .get(&format!("GET:{}", &url))
+        .await?
+        .expect("response should be cached");
+    assert!(stored.headers().get("set-cookie").is_none());
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+    assert!(res.headers().get("set-cookie").is_none());
+    stripped.assert();
+
+    // Not stored at all
+    let (client, manager) = cache(SetCookieMode::Refuse)?;
+    let url = format!("{}/set-cookie-refuse", &mockito::server_url());
+    for _ in 0..2 {
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.headers()["set-cookie"], "session=abc");
+    }
+    assert!(manager.is_empty());
+    refused.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn namespaces_partition_a_shared_manager() -> Result<()> {
+    let m = mock("GET", "/namespace")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("test")
+        .expect(2)
+        .create();
+    let url = format!("{}/namespace", &mockito::server_url());
+    let manager = HashMapManager::default();
+    let client = |namespace| -> Result<_> {
+        Ok(ClientBuilder::new(Client::new())
+            .with(
+                Cache::builder()
+                    .mode(CacheMode::Default)
+                    .cache_manager(manager.clone())
+                    .namespace(namespace)
+                    .build()?,
+            )
+            .build())
+    };
+    let tenant_a = client("tenant-a")?;
+    let tenant_b = client("tenant-b")?;
+
+    tenant_a.get(&url).send().await?;
+    assert!(manager.contains_key(&format!("tenant-a/GET:{}", &url)));
+    let res = tenant_a.get(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+
+    // Another namespace doesn't see the record
+    let res = tenant_b.get(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Miss));
+    assert_eq!(manager.len(), 2);
+    m.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn accept_encoding_can_be_part_of_the_key() -> Result<()> {
+    let gzip = mock("GET", "/encoding")
+        .match_header("accept-encoding", "gzip")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("gzip")
+        .expect(1)
+        .create();
+    let identity = mock("GET", "/encoding")
+        .match_header("accept-encoding", "identity")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("identity")
+        .expect(1)
+        .create();
+    let url = format!("{}/encoding", &mockito::server_url());
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(HashMapManager::default())
+                .accept_encoding_in_key(true)
+                .build()?,
+        )
+        .build();
+
+    for _ in 0..2 {
+        for encoding in &["gzip", "identity"] {
+            let res = client
+                .get(&url)
+                .header("accept-encoding", *encoding)
+                .send()
+                .await?;
+            assert_eq!(res.text().await?, *encoding);
+        }
+    }
+    gzip.assert();
+    identity.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn head_responses_are_stored_without_a_body() -> Result<()> {
+    let head = mock("HEAD", "/head-body")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("should not be here")
+        .expect(1)
+        .create();
+    let get = mock("GET", "/head-body")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("test")
+        .expect(1)
+        .create();
+    let url = format!("{}/head-body", &mockito::server_url());
+    let manager = HashMapManager::default();
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager.clone())
+                .build()?,
+        )
+        .build();
+
+    client.head(&url).send().await?;
+    let (stored, _) = manager
+        .get(&format!("HEAD:{}", &url))
+        .await?
+        .expect("response should be cached");
+    assert!(stored.bytes().await?.is_empty());
+    let res = client.head(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+    assert!(res.bytes().await?.is_empty());
+
+    // A GET isn't served the HEAD response
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.text().await?, "test");
+    head.assert();
+    get.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn surrogate_control_overrides_cache_control() -> Result<()> {
+    let surrogate_fresh = mock("GET", "/surrogate-fresh")
+        .with_status(200)
+        .with_header("cache-control", "no-cache, private")
+        .with_header("surrogate-control", "max-age=3600")
+        .with_body("test")
+        .expect(1)
+        .create();
+    let surrogate_no_store = mock("GET", "/surrogate-no-store")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_header("surrogate-control", "no-store")
+        .with_body("test")
+        .expect(2)
+        .create();
+    let manager = HashMapManager::default();
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager.clone())
+                .surrogate_control(true)
+                .build()?,
+        )
+        .build();
+
+    let url = format!("{}/surrogate-fresh", &mockito::server_url());
+    client.get(&url).send().await?;
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+    surrogate_fresh.assert();
+
+    let url = format!("{}/surrogate-no-store", &mockito::server_url());
+    client.get(&url).send().await?;
+    client.get(&url).send().await?;
+    assert!(!manager.contains_key(&format!("GET:{}", &url)));
+    surrogate_no_store.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn policy_options_tune_heuristic_freshness() -> Result<()> {
+    let ten_days_ago =
+        httpdate::fmt_http_date(SystemTime::now() - Duration::from_secs(10 * 24 * 3600));
+    let m = mock("GET", "/heuristic")
+        .with_status(200)
+        .with_header("last-modified", &ten_days_ago)
+        .with_body("test")
+        .expect(3)
+        .create();
+    let url = format!("{}/heuristic", &mockito::server_url());
+    for (cache_heuristic, second_pass) in &[(0.1, HitOrMiss::Hit), (0.0, HitOrMiss::Miss)] {
+        let client = ClientBuilder::new(Client::new())
+            .with(
+                Cache::builder()
+                    .mode(CacheMode::Default)
+                    .cache_manager(HashMapManager::default())
+                    .policy_options(CachePolicyOptions {
+                        cache_heuristic: *cache_heuristic,
+                        ..Default::default()
+                    })
+                    .build()?,
+            )
+            .build();
+        client.get(&url).send().await?;
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.extensions().get::<HitOrMiss>(), Some(second_pass));
+    }
+    m.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn partial_responses_are_not_stored() -> Result<()> {
+    let partial = mock("GET", "/range")
+        .match_header("range", "bytes=0-1")
+        .with_status(206)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_header("content-range", "bytes 0-1/4")
+        .with_body("te")
+        .expect(1)
+        .create();
+    let full = mock("GET", "/range")
+        .match_header("range", Matcher::Missing)
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("test")
+        .expect(1)
+        .create();
+    let url = format!("{}/range", &mockito::server_url());
+    let manager = HashMapManager::default();
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager.clone())
+                .build()?,
+        )
+        .build();
+
+    let res = client.get(&url).header("range", "bytes=0-1").send().await?;
+    assert_eq!(res.status(), 206);
+    assert!(manager.is_empty());
+
+    // A plain request isn't served the range as the whole body
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Miss));
+    assert_eq!(res.text().await?, "test");
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+    assert_eq!(res.text().await?, "test");
+    partial.assert();
+    full.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn connection_headers_are_never_replayed() -> Result<()> {
+    let m = mock("GET", "/hop-by-hop")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_header("keep-alive", "timeout=5")
+        .with_header("x-internal-trace", "abc")
+        .with_body("test")
+        .expect(1)
+        .create();
+    let manager = HashMapManager::default();
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager.clone())
+                .stripped_headers(vec![
+                    reqwest::header::CONNECTION,
+                    reqwest::header::HeaderName::from_static("keep-alive"),
+                    reqwest::header::HeaderName::from_static("x-internal-trace"),
+                ])
+                .build()?,
+        )
+        .build();
+
+    let url = format!("{}/hop-by-hop", &mockito::server_url());
+    client.get(&url).send().await?;
+    let (stored, _) = manager
+        .get(&format!("GET:{}", &url))
+        .await?
+        .expect("response should be cached");
+    for name in &["connection", "keep-alive", "x-internal-trace"] {
+        assert!(stored.headers().get(*name).is_none());
+    }
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+    for name in &["connection", "keep-alive", "x-internal-trace"] {
+        assert!(res.headers().get(*name).is_none());
+    }
+    m.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn revalidation_sends_both_validators() -> Result<()> {
+    let last_modified = "Wed, 21 Oct 2015 07:28:00 GMT";
+    let m = mock("GET", "/both-validators")
+        .match_header("if-none-match", Matcher::Missing)
+        .with_status(200)
+        .with_header("cache-control", "no-cache")
+        .with_header("etag", "\"abc\"")
+        .with_header("last-modified", last_modified)
+        .with_body("test")
+        .expect(1)
+        .create();
+    let not_modified = mock("GET", "/both-validators")
+        .match_header("if-none-match", "\"abc\"")
+        .match_header("if-modified-since", last_modified)
+        .with_status(304)
+        .with_header("etag", "\"abc\"")
+        .expect(1)
+        .create();
+    let url = format!("{}/both-validators", &mockito::server_url());
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(HashMapManager::default())
+                .build()?,
+        )
+        .build();
+
+    client.get(&url).send().await?;
+    m.assert();
+
+    // Origins honoring only one of the validators can still answer 304
+    let res = client.get(&url).send().await?;
+    not_modified.assert();
+    assert_eq!(
+        res.extensions().get::<HitOrMiss>(),
+        Some(&HitOrMiss::Revalidated)
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn inspect_reports_decisions_without_fetching() -> Result<()> {
+    let fresh = mock("GET", "/inspect-fresh")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("test")
+        .expect(1)
+        .create();
+    let stale = mock("GET", "/inspect-stale")
+        .with_status(200)
+        .with_header("cache-control", "no-cache")
+        .with_body("test")
+        .expect(1)
+        .create();
+    let cache = Cache::builder()
+        .mode(CacheMode::Default)
+        .cache_manager(HashMapManager::default())
+        .build()?;
+    let client = ClientBuilder::new(Client::new())
+        .with(cache.clone())
+        .build();
+    let get = |path: &str| -> Result<reqwest::Request> {
+        let url = format!("{}{}", &mockito::server_url(), path);
+        Ok(reqwest::Request::new(Method::GET, url.parse()?))
+    };
+
+    assert_eq!(
+        cache.inspect(&get("/inspect-fresh")?).await?,
+        CacheDecision::Miss
+    );
+    client.execute(get("/inspect-fresh")?).await?;
+    client.execute(get("/inspect-stale")?).await?;
+    assert_eq!(
+        cache.inspect(&get("/inspect-fresh")?).await?,
+        CacheDecision::FreshHit
+    );
+    assert_eq!(
+        cache.inspect(&get("/inspect-stale")?).await?,
+        CacheDecision::StaleNeedsRevalidate
+    );
+    let post = reqwest::Request::new(Method::POST, get("/inspect-fresh")?.url().clone());
+    assert_eq!(cache.inspect(&post).await?, CacheDecision::Bypass);
+
+    let offline = Cache {
+        mode: CacheMode::OnlyIfCached,
+        ..cache
+    };
+    assert_eq!(
+        offline.inspect(&get("/inspect-missing")?).await?,
+        CacheDecision::OnlyIfCachedMiss
+    );
+    assert_eq!(
+        offline.inspect(&get("/inspect-stale")?).await?,
+        CacheDecision::FreshHit
+    );
+    fresh.assert();
+    stale.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn must_understand_overrides_no_store_for_known_statuses() -> Result<()> {
+    let understood = mock("GET", "/must-understand")
+        .with_status(200)
+        .with_header("cache-control", "max-age=3600, must-understand, no-store")
+        .with_body("test")
+        .expect(1)
+        .create();
+    let unknown = mock("GET", "/must-understand-unknown")
+        .with_status(299)
+        .with_header("cache-control", "max-age=3600, must-understand, no-store")
+        .with_body("test")
+        .expect(2)
+        .create();
+    let manager = HashMapManager::default();
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager.clone())
+                .build()?,
+        )
+        .build();
+
+    let url = format!("{}/must-understand", &mockito::server_url());
+    client.get(&url).send().await?;
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+    understood.assert();
+
+    let url = format!("{}/must-understand-unknown", &mockito::server_url());
+    client.get(&url).send().await?;
+    client.get(&url).send().await?;
+    assert!(!manager.contains_key(&format!("GET:{}", &url)));
+    unknown.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn host_modes_override_the_cache_mode() -> Result<()> {
+    let m = mock("GET", "/host-mode")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("test")
+        .expect(3)
+        .create();
+    let manager = HashMapManager::default();
+    let host = reqwest::Url::parse(&mockito::server_url())?
+        .host_str()
+        .unwrap_or_default()
+        .to_owned();
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager.clone())
+                .host_mode(host, CacheMode::NoStore)
+                .build()?,
+        )
+        .build();
+
+    // The host never caches
+    let url = format!("{}/host-mode", &mockito::server_url());
+    client.get(&url).send().await?;
+    client.get(&url).send().await?;
+    assert!(!manager.contains_key(&format!("GET:{}", &url)));
+
+    // Unless the request asks for a mode of its own
+    let mut extensions = Extensions::new();
+    extensions.insert(CacheMode::Default);
+    client
+        .get(&url)
+        .send_with_extensions(&mut extensions)
+        .await?;
+    assert!(manager.contains_key(&format!("GET:{}", &url)));
+    m.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn shared_caches_only_store_authorized_responses_that_allow_it() -> Result<()> {
+    let private = mock("GET", "/authorized")
+        .match_header("authorization", "Bearer abc")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400")
+        .with_body("test")
+        .expect(3)
+        .create();
+    let public = mock("GET", "/authorized-public")
+        .match_header("authorization", "Bearer abc")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("test")
+        .expect(1)
+        .create();
+    let cache = |shared: bool| -> Result<_> {
+        let manager = HashMapManager::default();
+        let client = ClientBuilder::new(Client::new())
+            .with(
+                Cache::builder()
+                    .mode(CacheMode::Default)
+                    .cache_manager(manager.clone())
+                    .shared(shared)
+                    .build()?,
+            )
+            .build();
+        Ok((client, manager))
+    };
+
+    // Without opting in, the response is kept out of a shared cache
+    let (client, manager) = cache(true)?;
+    let url = format!("{}/authorized", &mockito::server_url());
+    for _ in 0..2 {
+        client.get(&url).bearer_auth("abc").send().await?;
+    }
+    assert!(!manager.contains_key(&format!("GET:{}", &url)));
+
+    // `public` allows it
+    let url = format!("{}/authorized-public", &mockito::server_url());
+    client.get(&url).bearer_auth("abc").send().await?;
+    let res = client.get(&url).bearer_auth("abc").send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+    public.assert();
+
+    // A private cache stores it either way
+    let (client, manager) = cache(false)?;
+    let url = format!("{}/authorized", &mockito::server_url());
+    client.get(&url).bearer_auth("abc").send().await?;
+    assert!(manager.contains_key(&format!("GET:{}", &url)));
+    private.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn fresh_hits_only_read_from_the_manager() -> Result<()> {
+    let m = mock("GET", "/recorded")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("test")
+        .expect(1)
+        .create();
+    let url = format!("{}/recorded", &mockito::server_url());
+    let manager = RecordingManager::new(HashMapManager::default());
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager.clone())
+                .build()?,
+        )
+        .build();
+
+    client.get(&url).send().await?;
+    let key = format!("GET:{}", &url);
+    assert_eq!(
+        manager.ops(),
+        vec![CacheOp::Get(key.clone()), CacheOp::Put(key.clone())]
+    );
+
+    manager.clear_ops();
+    client.get(&url).send().await?;
+    assert_eq!(manager.ops(), vec![CacheOp::Get(key)]);
+    m.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn not_modified_replaces_cache_control() -> Result<()> {
+    let m = mock("GET", "/updated-cache-control")
+        .match_header("if-none-match", Matcher::Missing)
+        .with_status(200)
+        .with_header("cache-control", "max-age=0")
+        .with_header("etag", "\"abc\"")
+        .with_body("test")
+        .expect(1)
+        .create();
+    let not_modified = mock("GET", "/updated-cache-control")
+        .match_header("if-none-match", "\"abc\"")
+        .with_status(304)
+        .with_header("cache-control", "max-age=600")
+        .with_header("etag", "\"abc\"")
+        .expect(1)
+        .create();
+    let url = format!("{}/updated-cache-control", &mockito::server_url());
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(HashMapManager::default())
+                .build()?,
+        )
+        .build();
+
+    client.get(&url).send().await?;
+    let res = client.get(&url).send().await?;
+    assert_eq!(
+        res.extensions().get::<HitOrMiss>(),
+        Some(&HitOrMiss::Revalidated)
+    );
+    let cache_control: Vec<_> = res.headers().get_all("cache-control").iter().collect();
+    assert_eq!(cache_control, vec!["max-age=600"]);
+
+    // The new freshness carries over to later requests
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+    assert_eq!(res.headers()["cache-control"], "max-age=600");
+    m.assert();
+    not_modified.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn negative_ttl_caches_error_responses() -> Result<()> {
+    let unavailable = mock("GET", "/negative-unavailable")
+        .with_status(503)
+        .with_body("down")
+        .expect(1)
+        .create();
+    let retry_after = mock("GET", "/negative-retry-after")
+        .with_status(503)
+        .with_header("retry-after", "0")
+        .with_body("down")
+        .expect(2)
+        .create();
+    let forbidden = mock("GET", "/negative-forbidden")
+        .with_status(403)
+        .with_body("no")
+        .expect(2)
+        .create();
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(HashMapManager::default())
+                .negative_ttl(Duration::from_secs(60))
+                .build()?,
+        )
+        .build();
+
+    let url = format!("{}/negative-unavailable", &mockito::server_url());
+    client.get(&url).send().await?;
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.status(), 503);
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+    assert_eq!(res.text().await?, "down");
+    unavailable.assert();
+
+    // `Retry-After` takes precedence over the negative TTL
+    let url = format!("{}/negative-retry-after", &mockito::server_url());
+    client.get(&url).send().await?;
+    client.get(&url).send().await?;
+    retry_after.assert();
+
+    // Statuses outside the set are never cached
+    let url = format!("{}/negative-forbidden", &mockito::server_url());
+    client.get(&url).send().await?;
+    client.get(&url).send().await?;
+    forbidden.assert();
+    Ok(())
+}
+
+#[cfg(feature = "hash-keys")]
+#[tokio::test]
+async fn request_bodies_can_be_part_of_the_key() -> Result<()> {
+    let users = mock("GET", "/graphql")
+        .match_body("{ users }")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("users")
+        .expect(1)
+        .create();
+    let teams = mock("GET", "/graphql")
+        .match_body("{ teams }")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_body("teams")
+        .expect(1)
+        .create();
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(HashMapManager::default())
+                .body_in_key(true)
+                .build()?,
+        )
+        .build();
+
+    let url = format!("{}/graphql", &mockito::server_url());
+    for query in &["{ users }", "{ teams }"] {
+        client.get(&url).body(*query).send().await?;
+    }
+    for (query, body) in &[("{ users }", "users"), ("{ teams }", "teams")] {
+        let res = client.get(&url).body(*query).send().await?;
+        assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+        assert_eq!(res.text().await?, *body);
+    }
+    users.assert();
+    teams.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn should_cache_can_veto_storage() -> Result<()> {
+    let m = mock("GET", "/vetoed")
+        .with_status(200)
+        .with_header("cache-control", "max-age=86400, public")
+        .with_header("x-preview", "1")
+        .with_body("test")
+        .expect(2)
+        .create();
+    let manager = HashMapManager::default();
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager.clone())
+                .should_cache(|_req, res| !res.headers().contains_key("x-preview"))
+                .build()?,
+        )
+        .build();
+
+    let url = format!("{}/vetoed", &mockito::server_url());
+    for _ in 0..2 {
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Miss));
+    }
+    assert!(!manager.contains_key(&format!("GET:{}", &url)));
+    m.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn override_ttl_replaces_the_response_lifetime() -> Result<()> {
+    let asset = mock("GET", "/static/app.js")
+        .with_status(200)
+        .with_header("cache-control", "no-cache")
+        .with_body("test")
+        .expect(1)
+        .create();
+    let api = mock("GET", "/api/overridden")
+        .with_status(200)
+        .with_header("cache-control", "no-cache")
+        .with_body("test")
+        .expect(2)
+        .create();
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(HashMapManager::default())
+                .override_ttl(|req, _res| {
+                    if req.url().path().starts_with("/static/") {
+                        Some(Duration::from_secs(7 * 24 * 3600))
+                    } else {
+                        None
+                    }
+                })
+                .build()?,
+        )
+        .build();
+
+    let url = format!("{}/static/app.js", &mockito::server_url());
+    client.get(&url).send().await?;
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.extensions().get::<HitOrMiss>(), Some(&HitOrMiss::Hit));
+    let freshness = res
+        .extensions()
+        .get::<CacheFreshness>()
+        .expect("freshness should be set");
+    assert!(freshness.time_to_live > Duration::from_secs(6 * 24 * 3600));
+    asset.assert();
+
+    // Responses the hook passes on keep their own lifetime
+    let url = format!("{}/api/overridden", &mockito::server_url());
+    client.get(&url).send().await?;
+    client.get(&url).send().await?;
+    api.assert();
+    Ok(())
+}
+
+#[tokio::test]
+async fn override_ttl_keeps_not_understood_responses_out() -> Result<()> {
+    let m = mock("GET", "/not-understood")
+        .with_status(201)
+        .with_header("cache-control", "must-understand, no-store")
+        .with_body("test")
+        .expect(2)
+        .create();
+    let manager = HashMapManager::default();
+    let client = ClientBuilder::new(Client::new())
+        .with(
+            Cache::builder()
+                .mode(CacheMode::Default)
+                .cache_manager(manager.clone())
+                .override_ttl(|_req, _res| Some(Duration::from_secs(3600)))
+                .build()?,
+        )
+        .build();
+
+    let url = format!("{}/not-understood", &mockito::server_url());
+    client.get(&url).send().await?;
+    client.get(&url).send().await?;
+    assert!(!manager.contains_key(&format!("GET:{}", &url)));
+    m.assert();
     Ok(())
 }